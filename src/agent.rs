@@ -1,34 +1,37 @@
-use crate::{util::run_ssh2_fn, Error};
-use async_io::Async;
+use crate::{transport::Transport, util::{run_ssh2_fn, Interrupted, Op}, Error};
 use ssh2::{self, PublicKey};
-use std::{convert::From, net::TcpStream, sync::Arc};
+use std::{convert::From, sync::Arc};
 
 /// See [`Agent`](ssh2::Agent).
 pub struct Agent {
     inner: ssh2::Agent,
     inner_session: ssh2::Session,
-    stream: Arc<Async<TcpStream>>,
+    stream: Arc<Transport>,
+    interrupted: Interrupted,
 }
 
 impl Agent {
-    pub(crate) fn new(agent: ssh2::Agent, session: ssh2::Session, stream: Arc<Async<TcpStream>>) -> Agent {
+    pub(crate) fn new(agent: ssh2::Agent, session: ssh2::Session, stream: Arc<Transport>) -> Agent {
         Agent {
             inner: agent,
             inner_session: session,
             stream,
+            interrupted: Interrupted::default(),
         }
     }
 
     /// See [`connect`](ssh2::Agent::connect).
     pub async fn connect(&mut self) -> Result<(), Error> {
         let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || inner.connect()).await
+        let op = Op::new("agent connect").on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || inner.connect()).await
     }
 
     /// See [`disconnect`](ssh2::Agent::disconnect).
     pub async fn disconnect(&mut self) -> Result<(), Error> {
         let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || inner.disconnect()).await
+        let op = Op::new("agent disconnect").on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || inner.disconnect()).await
     }
 
     /// See [`list_identities`](ssh2::Agent::list_identities).
@@ -43,7 +46,8 @@ impl Agent {
 
     /// See [`userauth`](ssh2::Agent::userauth).
     pub async fn userauth(&self, username: &str, identity: &PublicKey) -> Result<(), Error> {
-        run_ssh2_fn(&self.stream, &self.inner_session, || {
+        let op = Op::new("agent userauth").arg(username);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
             self.inner.userauth(username, identity)
         })
         .await
@@ -1,37 +1,53 @@
-use crate::{util::{run_ssh2_fn, poll_ssh2_io_op}, Error};
+use crate::{transport::Transport, util::{run_ssh2_fn, poll_ssh2_io_op, Interrupted, Op}, Error};
+#[cfg(feature = "tokio")]
+use crate::util::poll_read_buf;
+use async_io::Timer;
 use futures::prelude::*;
-use async_io::Async;
 use ssh2::{self, ExitSignal, ExtendedData, PtyModes, ReadWindow, Stream, WriteWindow};
 use std::{
     convert::From,
     io,
     io::{Read, Write},
-    net::TcpStream,
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
 };
 
 /// See [`Channel`](ssh2::Channel).
+///
+/// Flushing, with either the futures or the tokio I/O traits, sends what
+/// libssh2 has buffered for the session on to the socket. Unlike flushing an
+/// [`ssh2::Channel`], it does not discard incoming data that was not read
+/// yet.
 pub struct Channel {
-    inner: ssh2::Channel,
+    inner: Arc<Mutex<ssh2::Channel>>,
     inner_session: ssh2::Session,
-    stream: Arc<Async<TcpStream>>,
+    stream: Arc<Transport>,
+    read_timer: Option<Timer>,
+    write_timer: Option<Timer>,
+    interrupted: Interrupted,
 }
 
 impl Channel {
-    pub(crate) fn new(channel: ssh2::Channel, session: ssh2::Session, stream: Arc<Async<TcpStream>>) -> Channel {
+    pub(crate) fn new(channel: ssh2::Channel, session: ssh2::Session, stream: Arc<Transport>) -> Channel {
         Channel {
-            inner: channel,
+            inner: Arc::new(Mutex::new(channel)),
             inner_session: session,
             stream,
+            read_timer: None,
+            write_timer: None,
+            interrupted: Interrupted::default(),
         }
     }
 
     /// See [`setenv`](ssh2::Channel::setenv).
     pub async fn setenv(&mut self, var: &str, val: &str) -> Result<(), Error> {
-        let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || inner.setenv(var, val)).await
+        let inner = &self.inner;
+        let op = Op::new("setenv").arg(var).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
+            inner.lock().unwrap().setenv(var, val)
+        })
+        .await
     }
 
     /// See [`request_pty`](ssh2::Channel::request_pty).
@@ -41,9 +57,10 @@ impl Channel {
         mode: Option<PtyModes>,
         dim: Option<(u32, u32, u32, u32)>,
     ) -> Result<(), Error> {
-        let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || {
-            inner.request_pty(term, mode.clone(), dim)
+        let inner = &self.inner;
+        let op = Op::new("request_pty").arg(term).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
+            inner.lock().unwrap().request_pty(term, mode.clone(), dim)
         })
         .await
     }
@@ -56,9 +73,12 @@ impl Channel {
         width_px: Option<u32>,
         height_px: Option<u32>,
     ) -> Result<(), Error> {
-        let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || {
+        let inner = &self.inner;
+        let op = Op::new("request_pty_size").on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
             inner
+                .lock()
+                .unwrap()
                 .request_pty_size(width, height, width_px, height_px)
         })
         .await
@@ -66,20 +86,32 @@ impl Channel {
 
     /// See [`exec`](ssh2::Channel::exec).
     pub async fn exec(&mut self, command: &str) -> Result<(), Error> {
-        let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || inner.exec(command)).await
+        let inner = &self.inner;
+        let op = Op::new("exec").arg(command).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
+            inner.lock().unwrap().exec(command)
+        })
+        .await
     }
 
     /// See [`shell`](ssh2::Channel::shell).
     pub async fn shell(&mut self) -> Result<(), Error> {
-        let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || inner.shell()).await
+        let inner = &self.inner;
+        let op = Op::new("shell").on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
+            inner.lock().unwrap().shell()
+        })
+        .await
     }
 
     /// See [`subsystem`](ssh2::Channel::subsystem).
     pub async fn subsystem(&mut self, system: &str) -> Result<(), Error> {
-        let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || inner.subsystem(system)).await
+        let inner = &self.inner;
+        let op = Op::new("subsystem").arg(system).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
+            inner.lock().unwrap().subsystem(system)
+        })
+        .await
     }
 
     /// See [`process_startup`](ssh2::Channel::process_startup).
@@ -88,88 +120,183 @@ impl Channel {
         request: &str,
         message: Option<&str>,
     ) -> Result<(), Error> {
-        let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || {
-            inner.process_startup(request, message)
+        let inner = &self.inner;
+        let op = Op::new("process_startup").arg(request).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
+            inner.lock().unwrap().process_startup(request, message)
+        })
+        .await
+    }
+
+    /// Asks the server to forward agent connections from the command run
+    /// on this channel, by sending `auth-agent-req@openssh.com`. Call it
+    /// before [`exec`](Channel::exec) or [`shell`](Channel::shell).
+    ///
+    /// This only sets up the remote end: the server exports
+    /// `SSH_AUTH_SOCK`, but libssh2 drops the `auth-agent@openssh.com`
+    /// channels the server opens back for each connection to it, without
+    /// even refusing them. Remote clients that use the socket, such as
+    /// `git` or a nested `ssh`, wait for an agent that never answers
+    /// instead of falling back to their other authentication methods. Only
+    /// request it for commands that merely need the socket to exist.
+    pub async fn request_agent_forwarding(&mut self) -> Result<(), Error> {
+        let inner = &self.inner;
+        let op = Op::new("request_agent_forwarding").on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
+            inner
+                .lock()
+                .unwrap()
+                .process_startup("auth-agent-req@openssh.com", None)
         })
         .await
     }
 
     /// See [`stderr`](ssh2::Channel::stderr).
     pub fn stderr(&mut self) -> Stream {
-        self.inner.stderr()
+        self.inner.lock().unwrap().stderr()
     }
 
     /// See [`stream`](ssh2::Channel::stream).
     pub fn stream(&mut self, stream_id: i32) -> Stream {
-        self.inner.stream(stream_id)
+        self.inner.lock().unwrap().stream(stream_id)
+    }
+
+    /// An async handle on the channel's stderr.
+    ///
+    /// Unlike [`stderr`](Channel::stderr), which blocks, this can be read
+    /// while other tasks use the channel.
+    pub fn stderr_handle(&self) -> ChannelStream {
+        self.stream_handle(ssh2::EXTENDED_DATA_STDERR)
+    }
+
+    /// An async handle on the data stream `stream_id`, 0 being the primary
+    /// one, that can be used independently of this channel.
+    pub fn stream_handle(&self, stream_id: i32) -> ChannelStream {
+        ChannelStream {
+            inner: self.inner.clone(),
+            inner_session: self.inner_session.clone(),
+            stream: self.stream.clone(),
+            stream_id,
+            read_timer: None,
+            write_timer: None,
+        }
+    }
+
+    /// Splits the channel into a writer for its stdin and readers for its
+    /// stdout and stderr, which can be driven from different tasks.
+    ///
+    /// The channel itself stays usable, e.g. to wait for it to close and
+    /// read the exit status. Closing the writer sends EOF.
+    ///
+    /// ```rust,no_run
+    /// use futures::io::{AsyncReadExt, AsyncWriteExt};
+    ///
+    /// # async fn run(sess: &async_ssh2::Session) -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut channel = sess.channel_session().await?;
+    /// channel.exec("sort").await?;
+    /// let (mut stdin, mut stdout, mut stderr) = channel.split_stdio();
+    /// let writer = async move {
+    ///     stdin.write_all(b"b\na\n").await?;
+    ///     stdin.close().await
+    /// };
+    /// let (mut out, mut err) = (Vec::new(), Vec::new());
+    /// futures::try_join!(writer, stdout.read_to_end(&mut out), stderr.read_to_end(&mut err))?;
+    /// channel.wait_close().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn split_stdio(&self) -> (ChannelStream, ChannelStream, ChannelStream) {
+        (self.stream_handle(0), self.stream_handle(0), self.stderr_handle())
+    }
+
+    /// Attaches the operation, its target and the session's peer to `e`.
+    pub(crate) fn error(&self, op: Op<'_>, e: Error) -> Error {
+        op.error(&self.stream, e)
     }
 
     /// See [`handle_extended_data`](ssh2::Channel::handle_extended_data).
     pub async fn handle_extended_data(&mut self, mode: ExtendedData) -> Result<(), Error> {
-        let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || {
-            inner.handle_extended_data(mode)
+        let inner = &self.inner;
+        let op = Op::new("handle_extended_data").on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
+            inner.lock().unwrap().handle_extended_data(mode)
         })
         .await
     }
 
     /// See [`exit_status`](ssh2::Channel::exit_status).
     pub fn exit_status(&self) -> Result<i32, Error> {
-        self.inner.exit_status().map_err(From::from)
+        self.inner.lock().unwrap().exit_status().map_err(From::from)
     }
 
     /// See [`exit_signal`](ssh2::Channel::exit_signal).
     pub fn exit_signal(&self) -> Result<ExitSignal, Error> {
-        self.inner.exit_signal().map_err(From::from)
+        self.inner.lock().unwrap().exit_signal().map_err(From::from)
     }
 
     /// See [`read_window`](ssh2::Channel::read_window).
     pub fn read_window(&self) -> ReadWindow {
-        self.inner.read_window()
+        self.inner.lock().unwrap().read_window()
     }
 
     /// See [`write_window`](ssh2::Channel::write_window).
     pub fn write_window(&self) -> WriteWindow {
-        self.inner.write_window()
+        self.inner.lock().unwrap().write_window()
     }
 
     /// See [`adjust_receive_window`](ssh2::Channel::adjust_receive_window).
     pub async fn adjust_receive_window(&mut self, adjust: u64, force: bool) -> Result<u64, Error> {
-        let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || {
-            inner.adjust_receive_window(adjust, force)
+        let inner = &self.inner;
+        let op = Op::new("adjust_receive_window").on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
+            inner.lock().unwrap().adjust_receive_window(adjust, force)
         })
         .await
     }
 
     /// See [`eof`](ssh2::Channel::eof).
     pub fn eof(&self) -> bool {
-        self.inner.eof()
+        self.inner.lock().unwrap().eof()
     }
 
     /// See [`send_eof`](ssh2::Channel::send_eof).
     pub async fn send_eof(&mut self) -> Result<(), Error> {
-        let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || inner.send_eof()).await
+        let inner = &self.inner;
+        let op = Op::new("send_eof").on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
+            inner.lock().unwrap().send_eof()
+        })
+        .await
     }
 
     /// See [`wait_eof`](ssh2::Channel::wait_eof).
     pub async fn wait_eof(&mut self) -> Result<(), Error> {
-        let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || inner.wait_eof()).await
+        let inner = &self.inner;
+        let op = Op::new("wait_eof").abandonable();
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
+            inner.lock().unwrap().wait_eof()
+        })
+        .await
     }
 
     /// See [`close`](ssh2::Channel::close).
     pub async fn close(&mut self) -> Result<(), Error> {
-        let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || inner.close()).await
+        let inner = &self.inner;
+        let op = Op::new("close").on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
+            inner.lock().unwrap().close()
+        })
+        .await
     }
 
     /// See [`wait_close`](ssh2::Channel::wait_close).
     pub async fn wait_close(&mut self) -> Result<(), Error> {
-        let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream, &self.inner_session, || inner.wait_close()).await
+        let inner = &self.inner;
+        let op = Op::new("wait_close").abandonable();
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || {
+            inner.lock().unwrap().wait_close()
+        })
+        .await
     }
 }
 
@@ -180,8 +307,8 @@ impl AsyncRead for Channel {
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
         let this = self.get_mut();
-        let inner = &mut this.inner;
-        poll_ssh2_io_op(cx, &this.stream, &this.inner_session, || inner.read(buf))
+        let inner = &this.inner;
+        poll_ssh2_io_op(cx, &this.stream, &this.inner_session, &mut this.read_timer, || inner.lock().unwrap().read(buf))
     }
 }
 
@@ -192,24 +319,147 @@ impl AsyncWrite for Channel {
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
         let this = self.get_mut();
-        let inner = &mut this.inner;
-        poll_ssh2_io_op(cx, &this.stream, &this.inner_session, || inner.write(buf))
+        let inner = &this.inner;
+        poll_ssh2_io_op(cx, &this.stream, &this.inner_session, &mut this.write_timer, || inner.lock().unwrap().write(buf))
+    }
+
+    // libssh2's channel flush discards unread incoming data, which would
+    // lose output that is still to be read, see `ChannelStream`.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.stream.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let inner = &this.inner;
+        poll_ssh2_io_op(cx, &this.stream, &this.inner_session, &mut this.write_timer, || {
+            inner.lock().unwrap().close().map_err(|e| io::Error::from(ssh2::Error::from_errno(e.code())))
+        })
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for Channel {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        poll_read_buf(buf, |buf| AsyncRead::poll_read(self, cx, buf))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for Channel {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(self, cx, buf)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(self, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_close(self, cx)
+    }
+}
+
+/// An async handle on one data stream of a [`Channel`], see
+/// [`Channel::split_stdio`] and [`RemoteChild`](crate::RemoteChild).
+///
+/// Handles on the same channel can be used from different tasks. Closing a
+/// handle sends EOF on the channel, which is how a remote command's stdin is
+/// closed.
+pub struct ChannelStream {
+    inner: Arc<Mutex<ssh2::Channel>>,
+    inner_session: ssh2::Session,
+    stream: Arc<Transport>,
+    stream_id: i32,
+    read_timer: Option<Timer>,
+    write_timer: Option<Timer>,
+}
+
+impl ChannelStream {
+    /// The id of the stream, 0 for the primary one and
+    /// [`EXTENDED_DATA_STDERR`](ssh2::EXTENDED_DATA_STDERR) for stderr.
+    pub fn id(&self) -> i32 {
+        self.stream_id
+    }
+}
+
+impl AsyncRead for ChannelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
         let this = self.get_mut();
-        let inner = &mut this.inner;
-        poll_ssh2_io_op(cx, &this.stream, &this.inner_session, || inner.flush())
+        let (inner, id) = (&this.inner, this.stream_id);
+        poll_ssh2_io_op(cx, &this.stream, &this.inner_session, &mut this.read_timer, || {
+            inner.lock().unwrap().stream(id).read(buf)
+        })
+    }
+}
+
+impl AsyncWrite for ChannelStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let (inner, id) = (&this.inner, this.stream_id);
+        poll_ssh2_io_op(cx, &this.stream, &this.inner_session, &mut this.write_timer, || {
+            inner.lock().unwrap().stream(id).write(buf)
+        })
+    }
+
+    // libssh2's channel flush discards unread incoming data, which would
+    // lose the output other handles are waiting for.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.stream.poll_flush(cx)
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         let this = self.get_mut();
-        let inner = &mut this.inner;
-        poll_ssh2_io_op(cx, 
-            &this.stream, 
-            &this.inner_session, 
-            || inner.close().map_err(|e| io::Error::from(ssh2::Error::from_errno(e.code())))
-        )
+        let inner = &this.inner;
+        poll_ssh2_io_op(cx, &this.stream, &this.inner_session, &mut this.write_timer, || {
+            inner.lock().unwrap().send_eof().map_err(io::Error::from)
+        })
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for ChannelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        poll_read_buf(buf, |buf| AsyncRead::poll_read(self, cx, buf))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for ChannelStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(self, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(self, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_close(self, cx)
     }
 }
 
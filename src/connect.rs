@@ -0,0 +1,277 @@
+use crate::{
+    error::ConnectError,
+    host_key::{HostKeyVerifier, KnownHostsVerifier},
+    util::{unblock, wildcard_match},
+    Error, Session,
+};
+use async_io::{Async, Timer};
+use futures::{
+    future::{self, Either},
+    stream::FuturesUnordered,
+    StreamExt,
+};
+use ssh2::MethodType;
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Delay before the next address is tried while an earlier attempt is still
+/// pending, as recommended by RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Options used by [`Session::connect`](crate::Session::connect).
+///
+/// ```rust
+/// use async_ssh2::{ConnectOptions, KnownHostsVerifier};
+/// use std::time::Duration;
+///
+/// let options = ConnectOptions::new()
+///     .connect_timeout(Duration::from_secs(5))
+///     .happy_eyeballs(false)
+///     .host_key_verifier(KnownHostsVerifier::accept_new("/etc/app/known_hosts"));
+/// ```
+#[derive(Clone)]
+pub struct ConnectOptions {
+    connect_timeout: Option<Duration>,
+    happy_eyeballs: bool,
+    host_key_verifier: Arc<dyn HostKeyVerifier>,
+    method_prefs: Vec<(MethodType, String)>,
+    keepalive: Option<(bool, u32)>,
+    operation_timeout: Option<Duration>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            connect_timeout: None,
+            happy_eyeballs: true,
+            host_key_verifier: Arc::new(KnownHostsVerifier::strict(KnownHostsVerifier::user_file())),
+            method_prefs: Vec::new(),
+            keepalive: None,
+            operation_timeout: None,
+        }
+    }
+}
+
+impl fmt::Debug for ConnectOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectOptions")
+            .field("connect_timeout", &self.connect_timeout)
+            .field("happy_eyeballs", &self.happy_eyeballs)
+            .field("method_prefs", &self.method_prefs.iter().map(|(_, p)| p).collect::<Vec<_>>())
+            .field("keepalive", &self.keepalive)
+            .field("operation_timeout", &self.operation_timeout)
+            .finish()
+    }
+}
+
+impl ConnectOptions {
+    /// Creates options with no connect timeout, happy eyeballs enabled and
+    /// strict host key checking against `~/.ssh/known_hosts`.
+    pub fn new() -> ConnectOptions {
+        ConnectOptions::default()
+    }
+
+    /// Limits how long a single TCP connection attempt may take.
+    pub fn connect_timeout(mut self, timeout: Duration) -> ConnectOptions {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// When enabled (the default) resolved IPv6 and IPv4 addresses are
+    /// interleaved and attempts are raced in the style of RFC 8305. When
+    /// disabled the addresses are tried one after another in resolver order.
+    pub fn happy_eyeballs(mut self, enabled: bool) -> ConnectOptions {
+        self.happy_eyeballs = enabled;
+        self
+    }
+
+    /// Sets how the server's host key is verified after the handshake.
+    pub fn host_key_verifier<V: HostKeyVerifier + 'static>(mut self, verifier: V) -> ConnectOptions {
+        self.host_key_verifier = Arc::new(verifier);
+        self
+    }
+
+    /// Sets the algorithm preferences for `method_type` before the
+    /// handshake, see [`Session::method_pref`].
+    ///
+    /// Like in `ssh_config`, a list starting with `+` is appended to the
+    /// supported algorithms, one starting with `^` is put in front of them
+    /// and one starting with `-` removes the matching algorithms, which may
+    /// contain `*` and `?` wildcards.
+    pub fn method_pref(mut self, method_type: MethodType, prefs: &str) -> ConnectOptions {
+        self.method_prefs.push((method_type, prefs.to_owned()));
+        self
+    }
+
+    /// Makes the session send keepalive messages, see
+    /// [`Session::set_keepalive`].
+    pub fn keepalive(mut self, want_reply: bool, interval: u32) -> ConnectOptions {
+        self.keepalive = Some((want_reply, interval));
+        self
+    }
+
+    /// Limits how long any operation on the session, including the
+    /// handshake, may take, see [`Session::set_operation_timeout`].
+    pub fn operation_timeout(mut self, timeout: Duration) -> ConnectOptions {
+        self.operation_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn verifier(&self) -> &dyn HostKeyVerifier {
+        &*self.host_key_verifier
+    }
+
+    /// Applies the method preferences, keepalive settings and operation
+    /// timeout to a session that has not performed the handshake yet.
+    pub(crate) fn configure(&self, session: &Session) -> Result<(), Error> {
+        for (method_type, prefs) in &self.method_prefs {
+            let supported = session.supported_algs(*method_type)?;
+            session.method_pref(*method_type, &method_prefs(prefs, &supported))?;
+        }
+        if let Some((want_reply, interval)) = self.keepalive {
+            session.set_keepalive(want_reply, interval);
+        }
+        if self.operation_timeout.is_some() {
+            session.set_operation_timeout(self.operation_timeout);
+        }
+        Ok(())
+    }
+}
+
+/// Expands the `+`, `-` and `^` prefixes of an `ssh_config` algorithm list
+/// against the algorithms libssh2 supports.
+fn method_prefs(prefs: &str, supported: &[&str]) -> String {
+    let (prefix, list) = match prefs.chars().next() {
+        Some(c @ ('+' | '-' | '^')) => (c, &prefs[1..]),
+        _ => return prefs.to_owned(),
+    };
+    let list = list.split(',').filter(|name| !name.is_empty());
+    let algs: Vec<&str> = match prefix {
+        '+' => supported.iter().copied().chain(list).collect(),
+        '^' => list.chain(supported.iter().copied()).collect(),
+        _ => {
+            let removed = list.collect::<Vec<_>>();
+            supported
+                .iter()
+                .copied()
+                .filter(|alg| !removed.iter().any(|pattern| wildcard_match(pattern, alg)))
+                .collect()
+        }
+    };
+    let mut deduped = Vec::with_capacity(algs.len());
+    for alg in algs {
+        if !deduped.contains(&alg) {
+            deduped.push(alg);
+        }
+    }
+    deduped.join(",")
+}
+
+/// Resolves `host` without blocking the executor.
+async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let host = host.to_owned();
+    let addrs = unblock(move || (host.as_str(), port).to_socket_addrs()).await?;
+    Ok(addrs.collect())
+}
+
+/// Reorders `addrs` so that address families alternate, starting with the
+/// family of the first address.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_is_v6);
+    let mut ret = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => return ret,
+            (a, b) => ret.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+async fn connect_addr(
+    addr: SocketAddr,
+    timeout: Option<Duration>,
+) -> (SocketAddr, io::Result<Async<TcpStream>>) {
+    let connect = Async::<TcpStream>::connect(addr);
+    let res = match timeout {
+        Some(timeout) => {
+            futures_util::pin_mut!(connect);
+            match future::select(connect, Timer::after(timeout)).await {
+                Either::Left((res, _)) => res,
+                Either::Right(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection attempt timed out",
+                )),
+            }
+        }
+        None => connect.await,
+    };
+    (addr, res)
+}
+
+/// Resolves `host` and connects to the first address that accepts.
+pub(crate) async fn connect_tcp(
+    host: &str,
+    port: u16,
+    options: &ConnectOptions,
+) -> Result<Async<TcpStream>, Error> {
+    let mut addrs = resolve(host, port)
+        .await
+        .map_err(|e| ConnectError::new(host, port, vec![], Some(e)))?;
+    if addrs.is_empty() {
+        return Err(ConnectError::new(host, port, vec![], None).into());
+    }
+    if options.happy_eyeballs {
+        addrs = interleave(addrs);
+    }
+
+    let mut attempts = Vec::new();
+    let mut remaining = addrs.into_iter();
+    let mut pending = FuturesUnordered::new();
+    loop {
+        if pending.is_empty() {
+            match remaining.next() {
+                Some(addr) => pending.push(connect_addr(addr, options.connect_timeout)),
+                None => break,
+            }
+        }
+
+        let finished = if options.happy_eyeballs && remaining.len() > 0 {
+            match future::select(pending.next(), Timer::after(ATTEMPT_DELAY)).await {
+                Either::Left((finished, _)) => finished,
+                Either::Right(_) => {
+                    let addr = remaining.next().unwrap();
+                    pending.push(connect_addr(addr, options.connect_timeout));
+                    continue;
+                }
+            }
+        } else {
+            pending.next().await
+        };
+
+        match finished {
+            Some((_, Ok(stream))) => return Ok(stream),
+            Some((addr, Err(e))) => {
+                attempts.push((addr, e));
+                if let Some(addr) = remaining.next() {
+                    pending.push(connect_addr(addr, options.connect_timeout));
+                }
+            }
+            None => break,
+        }
+    }
+    Err(ConnectError::new(host, port, attempts, None).into())
+}
@@ -1,12 +1,256 @@
-use std::{convert::From, error, fmt, io};
+use crate::{util::base64_encode, Session};
+use libssh2_sys as raw;
+use ssh2::{ErrorCode, HashType, HostKeyType};
+use std::{convert::From, error, fmt, io, net::SocketAddr};
+
+/// The category of an [`Error`], see [`Error::kind`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The server rejected the credentials.
+    AuthenticationFailed,
+    /// The server's host key was rejected.
+    HostKey,
+    /// No common algorithms or a failed key exchange.
+    KeyExchange,
+    /// The server refused to open a channel.
+    ChannelOpenFailed,
+    /// The server denied a request, e.g. a port forward or an environment
+    /// variable.
+    RequestDenied,
+    /// The channel has already been closed or sent EOF.
+    ChannelClosed,
+    /// SFTP: no such file or path.
+    NotFound,
+    /// SFTP: permission denied or write protected.
+    PermissionDenied,
+    /// SFTP: the file already exists.
+    AlreadyExists,
+    /// SFTP: end of file or directory.
+    Eof,
+    /// SFTP: the server reported a generic failure.
+    SftpFailure,
+    /// An operation or connection attempt timed out.
+    Timeout,
+    /// The connection was closed by the peer.
+    Disconnected,
+    /// Reading from or writing to the socket failed.
+    Socket,
+    /// No connection to the server could be established.
+    ConnectFailed,
+    /// The peer violated the protocol or sent corrupt data.
+    Protocol,
+    /// The operation would block. Async methods never return this.
+    WouldBlock,
+    /// An earlier operation on the same object was canceled halfway through
+    /// and has to be retried before anything else can be done with it.
+    Interrupted,
+    /// An argument was rejected before anything was sent, e.g. a socket
+    /// path longer than a request can carry.
+    InvalidInput,
+    /// Anything else.
+    Other,
+}
+
+impl ErrorKind {
+    fn from_ssh2(e: &ssh2::Error) -> ErrorKind {
+        match e.code() {
+            ErrorCode::Session(code) => match code {
+                raw::LIBSSH2_ERROR_AUTHENTICATION_FAILED
+                | raw::LIBSSH2_ERROR_PUBLICKEY_UNVERIFIED
+                | raw::LIBSSH2_ERROR_PASSWORD_EXPIRED => ErrorKind::AuthenticationFailed,
+                raw::LIBSSH2_ERROR_KEX_FAILURE
+                | raw::LIBSSH2_ERROR_KEY_EXCHANGE_FAILURE
+                | raw::LIBSSH2_ERROR_HOSTKEY_INIT
+                | raw::LIBSSH2_ERROR_HOSTKEY_SIGN
+                | raw::LIBSSH2_ERROR_METHOD_NONE => ErrorKind::KeyExchange,
+                raw::LIBSSH2_ERROR_CHANNEL_FAILURE => ErrorKind::ChannelOpenFailed,
+                raw::LIBSSH2_ERROR_CHANNEL_REQUEST_DENIED | raw::LIBSSH2_ERROR_REQUEST_DENIED => {
+                    ErrorKind::RequestDenied
+                }
+                raw::LIBSSH2_ERROR_CHANNEL_CLOSED
+                | raw::LIBSSH2_ERROR_CHANNEL_EOF_SENT
+                | raw::LIBSSH2_ERROR_CHANNEL_UNKNOWN => ErrorKind::ChannelClosed,
+                raw::LIBSSH2_ERROR_TIMEOUT | raw::LIBSSH2_ERROR_SOCKET_TIMEOUT => ErrorKind::Timeout,
+                // A peer that hangs up before sending its banner is the
+                // usual cause of the latter.
+                raw::LIBSSH2_ERROR_SOCKET_DISCONNECT | raw::LIBSSH2_ERROR_BANNER_RECV => {
+                    ErrorKind::Disconnected
+                }
+                raw::LIBSSH2_ERROR_SOCKET_SEND
+                | raw::LIBSSH2_ERROR_SOCKET_RECV
+                | raw::LIBSSH2_ERROR_BAD_SOCKET
+                | raw::LIBSSH2_ERROR_BANNER_SEND => ErrorKind::Socket,
+                raw::LIBSSH2_ERROR_PROTO
+                | raw::LIBSSH2_ERROR_INVALID_MAC
+                | raw::LIBSSH2_ERROR_DECRYPT
+                | raw::LIBSSH2_ERROR_CHANNEL_OUTOFORDER
+                | raw::LIBSSH2_ERROR_CHANNEL_WINDOW_EXCEEDED
+                | raw::LIBSSH2_ERROR_CHANNEL_PACKET_EXCEEDED
+                | raw::LIBSSH2_ERROR_SCP_PROTOCOL
+                | raw::LIBSSH2_ERROR_SFTP_PROTOCOL
+                | raw::LIBSSH2_ERROR_PUBLICKEY_PROTOCOL
+                | raw::LIBSSH2_ERROR_AGENT_PROTOCOL => ErrorKind::Protocol,
+                raw::LIBSSH2_ERROR_EAGAIN => ErrorKind::WouldBlock,
+                _ => ErrorKind::Other,
+            },
+            ErrorCode::SFTP(code) => match code {
+                raw::LIBSSH2_FX_EOF => ErrorKind::Eof,
+                raw::LIBSSH2_FX_NO_SUCH_FILE | raw::LIBSSH2_FX_NO_SUCH_PATH => ErrorKind::NotFound,
+                raw::LIBSSH2_FX_PERMISSION_DENIED | raw::LIBSSH2_FX_WRITE_PROTECT => {
+                    ErrorKind::PermissionDenied
+                }
+                raw::LIBSSH2_FX_FILE_ALREADY_EXISTS => ErrorKind::AlreadyExists,
+                raw::LIBSSH2_FX_FAILURE => ErrorKind::SftpFailure,
+                raw::LIBSSH2_FX_NO_CONNECTION | raw::LIBSSH2_FX_CONNECTION_LOST => {
+                    ErrorKind::Disconnected
+                }
+                raw::LIBSSH2_FX_BAD_MESSAGE => ErrorKind::Protocol,
+                _ => ErrorKind::Other,
+            },
+        }
+    }
+
+    fn from_io(e: &io::Error) -> ErrorKind {
+        if let Some(e) = e.get_ref().and_then(|e| e.downcast_ref::<ssh2::Error>()) {
+            return ErrorKind::from_ssh2(e);
+        }
+        if e.get_ref().is_some_and(|e| e.is::<InterruptedError>()) {
+            return ErrorKind::Interrupted;
+        }
+        match e.kind() {
+            io::ErrorKind::NotFound => ErrorKind::NotFound,
+            io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+            io::ErrorKind::AlreadyExists => ErrorKind::AlreadyExists,
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::NotConnected => ErrorKind::Disconnected,
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::AddrNotAvailable => {
+                ErrorKind::ConnectFailed
+            }
+            io::ErrorKind::TimedOut => ErrorKind::Timeout,
+            io::ErrorKind::WouldBlock => ErrorKind::WouldBlock,
+            io::ErrorKind::InvalidData => ErrorKind::Protocol,
+            io::ErrorKind::InvalidInput => ErrorKind::InvalidInput,
+            io::ErrorKind::Interrupted | io::ErrorKind::WriteZero => ErrorKind::Socket,
+            _ => ErrorKind::Other,
+        }
+    }
+}
 
 /// Representation of an error.
+///
+/// Errors that wrap another one, like [`OperationError`], include its
+/// message in theirs and also return it from
+/// [`source`](error::Error::source), so that callers can get at the
+/// underlying [`ssh2::Error`] or [`io::Error`].
 #[derive(Debug)]
 pub enum Error {
     // An error that can occur within libssh2.
     SSH2(ssh2::Error),
     // An io error.
     Io(io::Error),
+    // No TCP connection could be established by `Session::connect`.
+    Connect(ConnectError),
+    // The proxy command of `Session::connect_command` failed.
+    ProxyCommand(ProxyCommandError),
+    // The server's host key was rejected by a `HostKeyVerifier`.
+    HostKey(HostKeyError),
+    // One of the above, annotated with the operation that failed.
+    Operation(OperationError),
+}
+
+impl Error {
+    /// What kind of failure this is, regardless of where it came from.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::SSH2(e) => ErrorKind::from_ssh2(e),
+            Error::Io(e) => ErrorKind::from_io(e),
+            Error::Connect(e) => match e.attempts() {
+                attempts if !attempts.is_empty()
+                    && attempts.iter().all(|(_, e)| e.kind() == io::ErrorKind::TimedOut) =>
+                {
+                    ErrorKind::Timeout
+                }
+                _ => ErrorKind::ConnectFailed,
+            },
+            Error::ProxyCommand(e) => e.error().kind(),
+            Error::HostKey(_) => ErrorKind::HostKey,
+            Error::Operation(e) => e.error().kind(),
+        }
+    }
+
+    /// The error this wraps, as the source of an error wrapping `self`.
+    ///
+    /// Skips `self`, whose message is that of the wrapped error.
+    fn as_source(&self) -> &(dyn error::Error + 'static) {
+        match self {
+            Error::SSH2(e) => e,
+            Error::Io(e) => e,
+            Error::Connect(e) => e,
+            Error::ProxyCommand(e) => e,
+            Error::HostKey(e) => e,
+            Error::Operation(e) => e,
+        }
+    }
+
+    fn operation_error(&self) -> Option<&OperationError> {
+        match self {
+            Error::Operation(e) => Some(e),
+            Error::ProxyCommand(e) => e.error().operation_error(),
+            _ => None,
+        }
+    }
+
+    /// The operation that failed, e.g. `"stat"` or `"exec"`.
+    pub fn operation(&self) -> Option<&str> {
+        self.operation_error().map(OperationError::operation)
+    }
+
+    /// The remote path, command or address the failed operation worked on.
+    pub fn target(&self) -> Option<&str> {
+        self.operation_error().and_then(OperationError::target)
+    }
+
+    /// The server the failed operation was sent to.
+    pub fn peer(&self) -> Option<&str> {
+        self.operation_error().and_then(OperationError::peer)
+    }
+
+    /// The remote file or path does not exist.
+    pub fn is_not_found(&self) -> bool {
+        self.kind() == ErrorKind::NotFound
+    }
+
+    /// Access to the remote file or path was denied.
+    pub fn is_permission_denied(&self) -> bool {
+        self.kind() == ErrorKind::PermissionDenied
+    }
+
+    /// The server rejected the credentials.
+    pub fn is_auth_failure(&self) -> bool {
+        self.kind() == ErrorKind::AuthenticationFailed
+    }
+
+    /// The operation or connection attempt timed out.
+    pub fn is_timeout(&self) -> bool {
+        self.kind() == ErrorKind::Timeout
+    }
+
+    /// The failure is likely transient: retrying, on a new connection if
+    /// need be, may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::Timeout
+                | ErrorKind::Disconnected
+                | ErrorKind::Socket
+                | ErrorKind::ConnectFailed
+                | ErrorKind::WouldBlock
+        )
+    }
 }
 
 impl fmt::Display for Error {
@@ -14,11 +258,23 @@ impl fmt::Display for Error {
         match self {
             Error::Io(e) => e.fmt(f),
             Error::SSH2(e) => e.fmt(f),
+            Error::Connect(e) => e.fmt(f),
+            Error::ProxyCommand(e) => e.fmt(f),
+            Error::HostKey(e) => e.fmt(f),
+            Error::Operation(e) => e.fmt(f),
         }
     }
 }
 
-impl error::Error for Error {}
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::ProxyCommand(e) => e.source(),
+            Error::Operation(e) => e.source(),
+            e => Some(e.as_source()),
+        }
+    }
+}
 
 impl From<ssh2::Error> for Error {
     fn from(e: ssh2::Error) -> Error {
@@ -31,3 +287,321 @@ impl From<io::Error> for Error {
         Error::Io(e)
     }
 }
+
+impl From<ConnectError> for Error {
+    fn from(e: ConnectError) -> Error {
+        Error::Connect(e)
+    }
+}
+
+impl From<ProxyCommandError> for Error {
+    fn from(e: ProxyCommandError) -> Error {
+        Error::ProxyCommand(e)
+    }
+}
+
+impl From<HostKeyError> for Error {
+    fn from(e: HostKeyError) -> Error {
+        Error::HostKey(e)
+    }
+}
+
+impl From<OperationError> for Error {
+    fn from(e: OperationError) -> Error {
+        Error::Operation(e)
+    }
+}
+
+/// Failure to establish a TCP connection to any address of a host.
+#[derive(Debug)]
+pub struct ConnectError {
+    host: String,
+    port: u16,
+    attempts: Vec<(SocketAddr, io::Error)>,
+    resolve_error: Option<io::Error>,
+}
+
+impl ConnectError {
+    pub(crate) fn new(
+        host: &str,
+        port: u16,
+        attempts: Vec<(SocketAddr, io::Error)>,
+        resolve_error: Option<io::Error>,
+    ) -> ConnectError {
+        ConnectError {
+            host: host.to_owned(),
+            port,
+            attempts,
+            resolve_error,
+        }
+    }
+
+    /// The host name that was passed to `connect`.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The port that was passed to `connect`.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Every address that was tried, in the order the attempts finished,
+    /// along with the reason it failed.
+    pub fn attempts(&self) -> &[(SocketAddr, io::Error)] {
+        &self.attempts
+    }
+
+    /// The name resolution error, if the host could not be resolved.
+    pub fn resolve_error(&self) -> Option<&io::Error> {
+        self.resolve_error.as_ref()
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(e) = &self.resolve_error {
+            return write!(f, "failed to resolve {}:{}: {}", self.host, self.port, e);
+        }
+        if self.attempts.is_empty() {
+            return write!(f, "{}:{} did not resolve to any address", self.host, self.port);
+        }
+        write!(f, "failed to connect to {}:{}", self.host, self.port)?;
+        for (i, (addr, e)) in self.attempts.iter().enumerate() {
+            let sep = if i == 0 { ": " } else { ", " };
+            write!(f, "{}{} ({})", sep, addr, e)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.resolve_error {
+            Some(e) => Some(e),
+            None => self.attempts.last().map(|(_, e)| e as _),
+        }
+    }
+}
+
+/// Failure to spawn a proxy command or to complete the handshake through it.
+#[derive(Debug)]
+pub struct ProxyCommandError {
+    program: String,
+    stderr: String,
+    source: Box<Error>,
+}
+
+impl ProxyCommandError {
+    pub(crate) fn new(program: &str, stderr: String, source: Error) -> ProxyCommandError {
+        ProxyCommandError {
+            program: program.to_owned(),
+            stderr,
+            source: Box::new(source),
+        }
+    }
+
+    /// The program that was spawned.
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// The tail of what the command wrote to stderr.
+    pub fn stderr(&self) -> &str {
+        &self.stderr
+    }
+
+    /// The underlying spawn or handshake error.
+    pub fn error(&self) -> &Error {
+        &self.source
+    }
+}
+
+impl fmt::Display for ProxyCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "proxy command `{}` failed: {}", self.program, self.source)?;
+        let stderr = self.stderr.trim_end();
+        if !stderr.is_empty() {
+            write!(f, " (stderr: {})", stderr)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ProxyCommandError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(self.source.as_source())
+    }
+}
+
+/// An error annotated with the operation that produced it.
+#[derive(Debug)]
+pub struct OperationError {
+    operation: &'static str,
+    target: Option<String>,
+    peer: Option<String>,
+    source: Box<Error>,
+}
+
+impl OperationError {
+    pub(crate) fn new(
+        operation: &'static str,
+        target: Option<String>,
+        peer: Option<String>,
+        source: Error,
+    ) -> OperationError {
+        OperationError {
+            operation,
+            target,
+            peer,
+            source: Box::new(source),
+        }
+    }
+
+    /// The operation that failed, e.g. `"stat"` or `"exec"`.
+    pub fn operation(&self) -> &str {
+        self.operation
+    }
+
+    /// The remote path, command or address the operation worked on.
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_deref()
+    }
+
+    /// The server the operation was sent to, as given to
+    /// [`Session::connect`] or the peer address of the socket.
+    pub fn peer(&self) -> Option<&str> {
+        self.peer.as_deref()
+    }
+
+    /// The underlying libssh2 or I/O error.
+    pub fn error(&self) -> &Error {
+        &self.source
+    }
+}
+
+impl fmt::Display for OperationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.operation)?;
+        if let Some(target) = &self.target {
+            write!(f, " `{}`", target)?;
+        }
+        if let Some(peer) = &self.peer {
+            write!(f, " on {}", peer)?;
+        }
+        write!(f, " failed: {}", self.source)
+    }
+}
+
+impl error::Error for OperationError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(self.source.as_source())
+    }
+}
+
+/// An operation was canceled halfway through; see `util::Interrupted`.
+#[derive(Debug)]
+struct InterruptedError(String);
+
+impl fmt::Display for InterruptedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} was canceled halfway through and has to be retried first",
+            self.0
+        )
+    }
+}
+
+impl error::Error for InterruptedError {}
+
+pub(crate) fn interrupted_error(operation: &str) -> io::Error {
+    io::Error::other(InterruptedError(operation.to_owned()))
+}
+
+/// Why a host key was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostKeyErrorKind {
+    /// There is no entry for the host.
+    Unknown,
+    /// The host is known with a different key.
+    Changed,
+    /// The key has been marked as `@revoked`.
+    Revoked,
+    /// The key does not match any of the pinned fingerprints.
+    NotPinned,
+    /// A custom verifier rejected the key.
+    Rejected,
+}
+
+/// The server's host key was rejected by a
+/// [`HostKeyVerifier`](crate::HostKeyVerifier).
+#[derive(Debug)]
+pub struct HostKeyError {
+    host: String,
+    port: u16,
+    key_type: Option<HostKeyType>,
+    fingerprint: String,
+    kind: HostKeyErrorKind,
+}
+
+impl HostKeyError {
+    /// Creates an error for the host key `session` received from `host:port`.
+    pub fn new(session: &Session, host: &str, port: u16, kind: HostKeyErrorKind) -> HostKeyError {
+        let fingerprint = match session.host_key_hash(HashType::Sha256) {
+            Some(hash) => format!("SHA256:{}", base64_encode(hash).trim_end_matches('=')),
+            None => String::new(),
+        };
+        HostKeyError {
+            host: host.to_owned(),
+            port,
+            key_type: session.host_key().map(|(_, key_type)| key_type),
+            fingerprint,
+            kind,
+        }
+    }
+
+    /// The host name that was connected to.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The port that was connected to.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The type of the rejected key.
+    pub fn key_type(&self) -> Option<HostKeyType> {
+        self.key_type
+    }
+
+    /// The SHA256 fingerprint of the rejected key in OpenSSH's format.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Why the key was rejected.
+    pub fn kind(&self) -> HostKeyErrorKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for HostKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self.kind {
+            HostKeyErrorKind::Unknown => "is not known",
+            HostKeyErrorKind::Changed => "has changed",
+            HostKeyErrorKind::Revoked => "has been revoked",
+            HostKeyErrorKind::NotPinned => "does not match any pinned fingerprint",
+            HostKeyErrorKind::Rejected => "was rejected",
+        };
+        write!(f, "host key for {}:{} {}", self.host, self.port, reason)?;
+        if !self.fingerprint.is_empty() {
+            write!(f, " ({})", self.fingerprint)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for HostKeyError {}
@@ -0,0 +1,764 @@
+use crate::{
+    channel::Channel,
+    connect::connect_tcp,
+    listener::Listener,
+    socks,
+    timeout::{timeout_error, with_timeout},
+    util::host_port,
+    ConnectOptions, Error, Session,
+};
+use async_io::{Async, Timer};
+use futures::{
+    channel::mpsc,
+    future,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    stream::{FuturesUnordered, Stream, StreamExt},
+    task::AtomicWaker,
+};
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::PathBuf};
+use std::{
+    fmt, io,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Forwards connections to a local address through the session to a host
+/// and port reachable from the server, like `ssh -L`.
+///
+/// Nothing happens until the future returned by [`run`](LocalForward::run)
+/// is polled; spawn it on the executor of your choice.
+///
+/// ```rust,no_run
+/// use async_ssh2::{LocalForward, Session};
+///
+/// # async fn run(sess: &Session) -> Result<(), async_ssh2::Error> {
+/// let forward = LocalForward::bind(sess, "127.0.0.1:5432".parse().unwrap(), "db.internal", 5432)?;
+/// let handle = forward.handle();
+/// let task = tokio::spawn(forward.run());
+/// // ...
+/// handle.shutdown();
+/// task.await.unwrap()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct LocalForward {
+    session: Session,
+    listener: Async<TcpListener>,
+    host: String,
+    port: u16,
+    shared: Arc<Shared>,
+}
+
+impl LocalForward {
+    /// Listens on `addr` for connections to forward to `host:port`, which is
+    /// resolved and connected to by the server.
+    pub fn bind(
+        session: &Session,
+        addr: SocketAddr,
+        host: &str,
+        port: u16,
+    ) -> Result<LocalForward, Error> {
+        Ok(LocalForward {
+            session: session.clone(),
+            listener: Async::<TcpListener>::bind(addr)?,
+            host: host.to_owned(),
+            port,
+            shared: Arc::default(),
+        })
+    }
+
+    /// The address the forward listens on, e.g. to find the port picked when
+    /// binding to port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.get_ref().local_addr()?)
+    }
+
+    /// A handle to watch and shut down the forward while it runs.
+    pub fn handle(&self) -> ForwardHandle {
+        ForwardHandle(self.shared.clone())
+    }
+
+    /// A stream of reports, one for each tunnel that has finished and one
+    /// for each connection that could not be accepted.
+    ///
+    /// Only the stream returned last receives reports.
+    pub fn reports(&mut self) -> Reports {
+        self.shared.reports()
+    }
+
+    /// Accepts connections and forwards each of them over a new channel
+    /// until the forward is shut down.
+    ///
+    /// After a [shutdown](ForwardHandle::shutdown), no more connections are
+    /// accepted and the future completes once the open tunnels are done.
+    /// Connections that cannot be accepted for lack of resources are
+    /// [reported](LocalForward::reports) and retried after a pause. Any other
+    /// failure to accept stops the forward like a shutdown and is returned
+    /// once the open tunnels are done.
+    pub async fn run(self) -> Result<(), Error> {
+        let LocalForward {
+            session,
+            listener,
+            host,
+            port,
+            shared,
+        } = self;
+        let mut tunnels = FuturesUnordered::new();
+        let mut result = Ok(());
+        let accept = |cx: &mut Context<'_>| poll_accept(&listener, cx);
+        while let Some(accepted) = shared.next_event(&mut tunnels, accept).await {
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if is_transient(&e) => {
+                    shared.report_accept_error(host_port(&host, port), e.into());
+                    if !shared.back_off(&mut tunnels).await {
+                        break;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    result = Err(e.into());
+                    break;
+                }
+            };
+            let (session, host, shared) = (session.clone(), host.clone(), shared.clone());
+            tunnels.push(Box::pin(async move {
+                let target = host_port(&host, port);
+                shared
+                    .track(peer.to_string(), target, |stats| async move {
+                        let src = (peer.ip().to_string(), peer.port());
+                        let channel = session
+                            .channel_direct_tcpip(&host, port, Some((&src.0, src.1)))
+                            .await?;
+                        tunnel(channel, &stream, stats).await
+                    })
+                    .await
+            }) as Tunnel);
+        }
+        drop(listener);
+        shared.drain(&mut tunnels).await;
+        result
+    }
+}
+
+impl fmt::Debug for LocalForward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LocalForward")
+            .field("local_addr", &self.listener.get_ref().local_addr().ok())
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+/// Forwards connections to a local SOCKS 4, 4a or 5 proxy through the
+/// session to wherever each client asks, like `ssh -D`.
+///
+/// Clients may name their targets by address or by host name; names are
+/// resolved by the server. Only CONNECT requests without authentication are
+/// supported.
+///
+/// Nothing happens until the future returned by [`run`](DynamicForward::run)
+/// is polled; spawn it on the executor of your choice.
+///
+/// ```rust,no_run
+/// use async_ssh2::{DynamicForward, Session};
+///
+/// # async fn run(sess: &Session) -> Result<(), async_ssh2::Error> {
+/// let forward = DynamicForward::bind(sess, "127.0.0.1:1080".parse().unwrap())?;
+/// let handle = forward.handle();
+/// let task = tokio::spawn(forward.run());
+/// // ...
+/// handle.shutdown();
+/// task.await.unwrap()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct DynamicForward {
+    session: Session,
+    listener: Async<TcpListener>,
+    handshake_timeout: Duration,
+    shared: Arc<Shared>,
+}
+
+impl DynamicForward {
+    /// Listens on `addr` for SOCKS clients.
+    pub fn bind(session: &Session, addr: SocketAddr) -> Result<DynamicForward, Error> {
+        Ok(DynamicForward {
+            session: session.clone(),
+            listener: Async::<TcpListener>::bind(addr)?,
+            handshake_timeout: SOCKS_HANDSHAKE_TIMEOUT,
+            shared: Arc::default(),
+        })
+    }
+
+    /// Limits how long a client may take to send its SOCKS request, 10
+    /// seconds by default. Clients that take longer are disconnected.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// The address the proxy listens on, e.g. to find the port picked when
+    /// binding to port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.get_ref().local_addr()?)
+    }
+
+    /// A handle to watch and shut down the forward while it runs.
+    pub fn handle(&self) -> ForwardHandle {
+        ForwardHandle(self.shared.clone())
+    }
+
+    /// A stream of reports, one for each tunnel that has finished, including
+    /// those whose SOCKS request failed, and one for each connection that
+    /// could not be accepted.
+    ///
+    /// Only the stream returned last receives reports.
+    pub fn reports(&mut self) -> Reports {
+        self.shared.reports()
+    }
+
+    /// Accepts clients and forwards each of them over a new channel to the
+    /// target it asks for until the forward is shut down.
+    ///
+    /// If the server refuses to open the channel, the client gets an error
+    /// reply telling why as far as SOCKS allows. After a
+    /// [shutdown](ForwardHandle::shutdown), no more clients are accepted and
+    /// the future completes once the open tunnels are done. Accept errors are
+    /// handled like by [`LocalForward::run`].
+    pub async fn run(self) -> Result<(), Error> {
+        let DynamicForward {
+            session,
+            listener,
+            handshake_timeout,
+            shared,
+        } = self;
+        let mut tunnels = FuturesUnordered::new();
+        let mut result = Ok(());
+        let accept = |cx: &mut Context<'_>| poll_accept(&listener, cx);
+        while let Some(accepted) = shared.next_event(&mut tunnels, accept).await {
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if is_transient(&e) => {
+                    shared.report_accept_error(String::new(), e.into());
+                    if !shared.back_off(&mut tunnels).await {
+                        break;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    result = Err(e.into());
+                    break;
+                }
+            };
+            let (session, shared) = (session.clone(), shared.clone());
+            tunnels.push(Box::pin(async move {
+                // The target is only known once the client has asked for it.
+                shared
+                    .track(peer.to_string(), String::new(), |stats| async move {
+                        let mut client = &stream;
+                        let read = socks::Request::read(&mut client);
+                        let timer = Timer::after(handshake_timeout);
+                        let request = match future::select(Box::pin(read), timer).await {
+                            future::Either::Left((request, _)) => request?,
+                            future::Either::Right(_) => {
+                                return Err(timeout_error(handshake_timeout).into())
+                            }
+                        };
+                        stats.set_target(host_port(&request.host, request.port));
+                        let src = (peer.ip().to_string(), peer.port());
+                        let opened = session
+                            .channel_direct_tcpip(
+                                &request.host,
+                                request.port,
+                                Some((&src.0, src.1)),
+                            )
+                            .await;
+                        match opened {
+                            Ok(channel) => {
+                                request.reply(&mut &stream, None).await?;
+                                tunnel(channel, &stream, stats).await
+                            }
+                            Err(e) => {
+                                // The client may be gone already, and the
+                                // channel's error tells more anyway.
+                                let _ = request.reply(&mut &stream, Some(&e)).await;
+                                Err(e)
+                            }
+                        }
+                    })
+                    .await
+            }) as Tunnel);
+        }
+        drop(listener);
+        shared.drain(&mut tunnels).await;
+        result
+    }
+}
+
+impl fmt::Debug for DynamicForward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DynamicForward")
+            .field("local_addr", &self.listener.get_ref().local_addr().ok())
+            .finish()
+    }
+}
+
+/// Forwards connections the server accepts on a port to a local target,
+/// like `ssh -R`.
+///
+/// Nothing happens until the future returned by [`run`](RemoteForward::run)
+/// is polled; spawn it on the executor of your choice.
+///
+/// ```rust,no_run
+/// use async_ssh2::{ForwardTarget, RemoteForward, Session};
+///
+/// # async fn run(sess: &Session) -> Result<(), async_ssh2::Error> {
+/// let target = ForwardTarget::Tcp("localhost".into(), 8080);
+/// let forward = RemoteForward::bind(sess, None, 8080, target).await?;
+/// let handle = forward.handle();
+/// let task = tokio::spawn(forward.run());
+/// // ...
+/// handle.shutdown();
+/// task.await.unwrap()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct RemoteForward {
+    listener: Listener,
+    addr: String,
+    target: ForwardTarget,
+    shared: Arc<Shared>,
+}
+
+impl RemoteForward {
+    /// Asks the server to listen on `host:port` and forward the connections
+    /// to `target`. See
+    /// [`channel_forward_listen`](Session::channel_forward_listen) for what
+    /// `host` and a `port` of 0 mean.
+    pub async fn bind(
+        session: &Session,
+        host: Option<&str>,
+        port: u16,
+        target: ForwardTarget,
+    ) -> Result<RemoteForward, Error> {
+        let (listener, port) = session.channel_forward_listen(port, host, None).await?;
+        Ok(RemoteForward {
+            listener,
+            addr: host_port(host.unwrap_or("localhost"), port),
+            target,
+            shared: Arc::default(),
+        })
+    }
+
+    /// The address the server listens on.
+    pub fn remote_addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// A handle to watch and shut down the forward while it runs.
+    pub fn handle(&self) -> ForwardHandle {
+        ForwardHandle(self.shared.clone())
+    }
+
+    /// A stream of reports, one for each tunnel that has finished.
+    ///
+    /// Only the stream returned last receives reports.
+    pub fn reports(&mut self) -> Reports {
+        self.shared.reports()
+    }
+
+    /// Accepts connections and forwards each of them to the target until the
+    /// forward is shut down.
+    ///
+    /// After a [shutdown](ForwardHandle::shutdown), the server is asked to
+    /// stop listening and the future completes once the open tunnels are
+    /// done. If accepting a connection fails, the forward stops the same way
+    /// and fails with that error. It also fails if canceling the forward
+    /// does.
+    pub async fn run(self) -> Result<(), Error> {
+        let RemoteForward {
+            mut listener,
+            addr,
+            target,
+            shared,
+        } = self;
+        let mut tunnels = FuturesUnordered::new();
+        let mut accept_error = None;
+        while let Some(accepted) = shared
+            .next_event(&mut tunnels, |cx| listener.poll_accept(cx))
+            .await
+        {
+            let channel = match accepted {
+                Ok(channel) => channel,
+                Err(e) => {
+                    accept_error = Some(e);
+                    break;
+                }
+            };
+            let (addr, target, shared) = (addr.clone(), target.clone(), shared.clone());
+            tunnels.push(Box::pin(async move {
+                shared
+                    .track(addr, target.to_string(), |stats| async move {
+                        target.connect_and_tunnel(channel, stats).await
+                    })
+                    .await
+            }) as Tunnel);
+        }
+        let canceled = listener.cancel().await;
+        shared.drain(&mut tunnels).await;
+        // Canceling fails as well if the session is gone, but the accept
+        // error tells why.
+        match accept_error {
+            Some(e) => Err(e),
+            None => canceled,
+        }
+    }
+}
+
+impl fmt::Debug for RemoteForward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RemoteForward")
+            .field("remote_addr", &self.addr)
+            .field("target", &self.target)
+            .finish()
+    }
+}
+
+/// Where a [`RemoteForward`] sends the connections the server accepts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForwardTarget {
+    /// A host and port, resolved and connected to locally.
+    Tcp(String, u16),
+    /// A Unix domain socket.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ForwardTarget {
+    /// Connects to the target and tunnels `channel` to it.
+    async fn connect_and_tunnel(&self, channel: Channel, counters: Arc<Counters>) -> Result<(), Error> {
+        match self {
+            ForwardTarget::Tcp(host, port) => {
+                let stream = connect_tcp(host, *port, &ConnectOptions::new()).await?;
+                tunnel(channel, &stream, counters).await
+            }
+            #[cfg(unix)]
+            ForwardTarget::Unix(path) => {
+                let stream = Async::<UnixStream>::connect(path).await?;
+                tunnel(channel, &stream, counters).await
+            }
+        }
+    }
+}
+
+impl fmt::Display for ForwardTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ForwardTarget::Tcp(host, port) => f.write_str(&host_port(host, *port)),
+            #[cfg(unix)]
+            ForwardTarget::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// How long a SOCKS client may take to send its request by default.
+const SOCKS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before accepting again after running out of file
+/// descriptors or memory, which would otherwise fail again right away.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Whether accepting failed for a reason that may go away by itself, such as
+/// running out of file descriptors or the client giving up.
+fn is_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::OutOfMemory => return true,
+        _ => {}
+    }
+    #[cfg(unix)]
+    {
+        let resources = [libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::ENOMEM];
+        if let Some(code) = e.raw_os_error() {
+            return resources.contains(&code);
+        }
+    }
+    false
+}
+
+/// Accepts a connection once the listener is readable.
+fn poll_accept(
+    listener: &Async<TcpListener>,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<(Async<TcpStream>, SocketAddr)>> {
+    loop {
+        futures::ready!(listener.poll_readable(cx))?;
+        match listener.get_ref().accept() {
+            Ok((stream, peer)) => return Poll::Ready(Async::new(stream).map(|s| (s, peer))),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            // The client gave up before we got to it.
+            Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => {}
+            Err(e) => return Poll::Ready(Err(e)),
+        }
+    }
+}
+
+/// A handle on a running forward.
+#[derive(Clone)]
+pub struct ForwardHandle(Arc<Shared>);
+
+impl ForwardHandle {
+    /// Stops accepting connections. The tunnels that are open keep running
+    /// until they are done.
+    pub fn shutdown(&self) {
+        self.0.shutdown.store(true, Ordering::SeqCst);
+        self.0.waker.wake();
+    }
+
+    /// Whether [`shutdown`](ForwardHandle::shutdown) was called.
+    pub fn is_shut_down(&self) -> bool {
+        self.0.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// The tunnels that are open right now.
+    pub fn tunnels(&self) -> Vec<TunnelStats> {
+        let tunnels = self.0.tunnels.lock().unwrap();
+        tunnels.iter().map(|tunnel| tunnel.snapshot()).collect()
+    }
+}
+
+impl fmt::Debug for ForwardHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ForwardHandle")
+            .field("shut_down", &self.is_shut_down())
+            .field("tunnels", &self.0.tunnels.lock().unwrap().len())
+            .finish()
+    }
+}
+
+/// The traffic of one forwarded connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelStats {
+    /// Where the connection came from. libssh2 does not tell where
+    /// connections to a [`RemoteForward`] came from, so it is the address the
+    /// server accepted them on. Empty if the connection could not be
+    /// accepted.
+    pub peer: String,
+    /// Where it is forwarded to. Empty while a [`DynamicForward`] client has
+    /// not told yet.
+    pub target: String,
+    /// Bytes forwarded from the peer to the target.
+    pub sent: u64,
+    /// Bytes forwarded from the target to the peer.
+    pub received: u64,
+}
+
+/// How a forwarded connection went, see [`LocalForward::reports`],
+/// [`DynamicForward::reports`] and [`RemoteForward::reports`].
+#[derive(Debug)]
+pub struct TunnelReport {
+    /// The traffic up to the end.
+    pub stats: TunnelStats,
+    /// Why the tunnel failed, if it did.
+    pub error: Option<Error>,
+}
+
+/// The stream returned by [`LocalForward::reports`],
+/// [`DynamicForward::reports`] and [`RemoteForward::reports`].
+#[derive(Debug)]
+pub struct Reports(mpsc::UnboundedReceiver<TunnelReport>);
+
+impl Stream for Reports {
+    type Item = TunnelReport;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<TunnelReport>> {
+        self.0.poll_next_unpin(cx)
+    }
+}
+
+type Tunnel = Pin<Box<dyn future::Future<Output = ()> + Send>>;
+
+/// The state a forward shares with its handles.
+#[derive(Default)]
+struct Shared {
+    shutdown: AtomicBool,
+    waker: AtomicWaker,
+    tunnels: Mutex<Vec<Arc<Counters>>>,
+    reports: Mutex<Option<mpsc::UnboundedSender<TunnelReport>>>,
+}
+
+impl Shared {
+    fn reports(&self) -> Reports {
+        let (tx, rx) = mpsc::unbounded();
+        *self.reports.lock().unwrap() = Some(tx);
+        Reports(rx)
+    }
+
+    /// Drives the tunnels until `accept` yields a connection, or returns
+    /// `None` once the forward is shut down.
+    async fn next_event<T>(
+        &self,
+        tunnels: &mut FuturesUnordered<Tunnel>,
+        mut accept: impl FnMut(&mut Context<'_>) -> Poll<T>,
+    ) -> Option<T> {
+        future::poll_fn(|cx| {
+            while let Poll::Ready(Some(())) = tunnels.poll_next_unpin(cx) {}
+            self.waker.register(cx.waker());
+            if self.shutdown.load(Ordering::SeqCst) {
+                return Poll::Ready(None);
+            }
+            accept(cx).map(Some)
+        })
+        .await
+    }
+
+    /// Drives the tunnels for [`ACCEPT_BACKOFF`], or returns `false` once the
+    /// forward is shut down.
+    async fn back_off(&self, tunnels: &mut FuturesUnordered<Tunnel>) -> bool {
+        let mut timer = Timer::after(ACCEPT_BACKOFF);
+        let elapsed = |cx: &mut Context<'_>| future::Future::poll(Pin::new(&mut timer), cx);
+        self.next_event(tunnels, elapsed).await.is_some()
+    }
+
+    /// Drives the tunnels until they are all done.
+    async fn drain(&self, tunnels: &mut FuturesUnordered<Tunnel>) {
+        while tunnels.next().await.is_some() {}
+    }
+
+    /// Runs `tunnel` as an active tunnel and reports how it went.
+    async fn track<F, Fut>(&self, peer: String, target: String, tunnel: F)
+    where
+        F: FnOnce(Arc<Counters>) -> Fut,
+        Fut: future::Future<Output = Result<(), Error>>,
+    {
+        let counters = Arc::new(Counters {
+            peer,
+            target: Mutex::new(target),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+        });
+        self.tunnels.lock().unwrap().push(counters.clone());
+        let result = tunnel(counters.clone()).await;
+        self.tunnels
+            .lock()
+            .unwrap()
+            .retain(|tunnel| !Arc::ptr_eq(tunnel, &counters));
+        self.report(TunnelReport {
+            stats: counters.snapshot(),
+            error: result.err(),
+        });
+    }
+
+    /// Reports a connection to `target` that could not be accepted.
+    fn report_accept_error(&self, target: String, error: Error) {
+        self.report(TunnelReport {
+            stats: TunnelStats {
+                peer: String::new(),
+                target,
+                sent: 0,
+                received: 0,
+            },
+            error: Some(error),
+        });
+    }
+
+    fn report(&self, report: TunnelReport) {
+        if let Some(reports) = &*self.reports.lock().unwrap() {
+            let _ = reports.unbounded_send(report);
+        }
+    }
+}
+
+/// The live traffic counters of a tunnel.
+struct Counters {
+    peer: String,
+    target: Mutex<String>,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Counters {
+    fn set_target(&self, target: String) {
+        *self.target.lock().unwrap() = target;
+    }
+
+    fn snapshot(&self) -> TunnelStats {
+        TunnelStats {
+            peer: self.peer.clone(),
+            target: self.target.lock().unwrap().clone(),
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A local stream that can be closed for writing only, so EOF from the
+/// server reaches the peer while it may still send.
+trait HalfClose {
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+impl HalfClose for Async<TcpStream> {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.get_ref().shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(unix)]
+impl HalfClose for Async<UnixStream> {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.get_ref().shutdown(Shutdown::Write)
+    }
+}
+
+/// Copies data both ways between `channel` and `stream` until both sides
+/// have sent EOF, then closes the channel.
+///
+/// Tunnels are often idle for long, so the session's operation timeout does
+/// not apply.
+async fn tunnel<S>(mut channel: Channel, stream: &S, counters: Arc<Counters>) -> Result<(), Error>
+where
+    S: HalfClose,
+    for<'a> &'a S: AsyncRead + AsyncWrite,
+{
+    let (mut input, mut output, _) = channel.split_stdio();
+    let upstream = async {
+        copy(stream, &mut input, &counters.sent).await?;
+        input.close().await
+    };
+    let downstream = async {
+        copy(&mut output, stream, &counters.received).await?;
+        stream.shutdown_write()
+    };
+    let result = with_timeout(None, future::try_join(upstream, downstream)).await;
+    let closed = channel.close().await;
+    result?;
+    closed
+}
+
+/// Copies `reader` to `writer` until EOF, counting the bytes in `count`.
+async fn copy<R, W>(mut reader: R, mut writer: W, count: &AtomicU64) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; 32 * 1024];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return writer.flush().await;
+        }
+        writer.write_all(&buf[..n]).await?;
+        count.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
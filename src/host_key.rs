@@ -0,0 +1,171 @@
+use crate::{
+    error::{HostKeyError, HostKeyErrorKind},
+    known_hosts::{KnownHostStatus, KnownHostsFile},
+    util::home_dir,
+    Error, Session,
+};
+use futures::future::{self, BoxFuture};
+use ssh2::HashType;
+use std::path::{Path, PathBuf};
+
+/// Decides whether the host key a server presented during the handshake is
+/// trusted.
+///
+/// [`Session::connect`] and friends call the verifier configured in
+/// [`ConnectOptions`](crate::ConnectOptions) right after the handshake and
+/// fail if it returns an error. Implementations can inspect the key with
+/// [`Session::host_key`] and [`Session::host_key_hash`] and should report a
+/// rejected key as [`Error::HostKey`].
+pub trait HostKeyVerifier: Send + Sync {
+    /// Verifies the host key of `session`, which is connected to `host:port`.
+    fn verify<'a>(
+        &'a self,
+        session: &'a Session,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// Checks host keys against an OpenSSH `known_hosts` file.
+#[derive(Clone, Debug)]
+pub struct KnownHostsVerifier {
+    path: PathBuf,
+    accept_new: bool,
+}
+
+impl KnownHostsVerifier {
+    /// Only accepts hosts that are listed in `path` with the presented key,
+    /// like `StrictHostKeyChecking yes`.
+    pub fn strict<P: AsRef<Path>>(path: P) -> KnownHostsVerifier {
+        KnownHostsVerifier {
+            path: path.as_ref().to_owned(),
+            accept_new: false,
+        }
+    }
+
+    /// Appends the key of hosts not listed in `path` yet (trust on first
+    /// use), but still rejects changed keys, like
+    /// `StrictHostKeyChecking accept-new`.
+    pub fn accept_new<P: AsRef<Path>>(path: P) -> KnownHostsVerifier {
+        KnownHostsVerifier {
+            path: path.as_ref().to_owned(),
+            accept_new: true,
+        }
+    }
+
+    /// The user's `~/.ssh/known_hosts`.
+    pub fn user_file() -> PathBuf {
+        home_dir().join(".ssh").join("known_hosts")
+    }
+
+    /// The path of the `known_hosts` file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    async fn check(&self, session: &Session, host: &str, port: u16) -> Result<(), Error> {
+        let key = match session.host_key() {
+            Some((key, _)) => key,
+            None => {
+                let kind = HostKeyErrorKind::Unknown;
+                return Err(HostKeyError::new(session, host, port, kind).into());
+            }
+        };
+        let mut known_hosts = KnownHostsFile::open(&self.path).await?;
+        let kind = match known_hosts.check(host, port, key) {
+            KnownHostStatus::Match => return Ok(()),
+            KnownHostStatus::Mismatch => HostKeyErrorKind::Changed,
+            KnownHostStatus::Revoked => HostKeyErrorKind::Revoked,
+            // Appending rather than saving keeps the keys of other hosts
+            // accepted meanwhile, e.g. by concurrent connections.
+            KnownHostStatus::NotFound if self.accept_new => {
+                return known_hosts.append(host, port, key, false).await;
+            }
+            KnownHostStatus::NotFound => HostKeyErrorKind::Unknown,
+        };
+        Err(HostKeyError::new(session, host, port, kind).into())
+    }
+}
+
+impl HostKeyVerifier for KnownHostsVerifier {
+    fn verify<'a>(
+        &'a self,
+        session: &'a Session,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(self.check(session, host, port))
+    }
+}
+
+/// Only accepts host keys whose fingerprint was pinned ahead of time.
+///
+/// ```rust
+/// use async_ssh2::{FingerprintVerifier, HashType};
+///
+/// let current = [0u8; 32];
+/// let next = [1u8; 32];
+/// let verifier = FingerprintVerifier::new(HashType::Sha256)
+///     .pin(&current)
+///     .pin(&next);
+/// ```
+#[derive(Clone, Debug)]
+pub struct FingerprintVerifier {
+    hash_type: HashType,
+    fingerprints: Vec<Vec<u8>>,
+}
+
+impl FingerprintVerifier {
+    /// Creates a verifier comparing fingerprints computed with `hash_type`,
+    /// see [`Session::host_key_hash`]. It rejects every key until
+    /// fingerprints are pinned.
+    pub fn new(hash_type: HashType) -> FingerprintVerifier {
+        FingerprintVerifier {
+            hash_type,
+            fingerprints: Vec::new(),
+        }
+    }
+
+    /// Adds a trusted fingerprint. Pinning several allows for key rotation.
+    pub fn pin(mut self, fingerprint: &[u8]) -> FingerprintVerifier {
+        self.fingerprints.push(fingerprint.to_vec());
+        self
+    }
+}
+
+impl HostKeyVerifier for FingerprintVerifier {
+    fn verify<'a>(
+        &'a self,
+        session: &'a Session,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let pinned = session
+            .host_key_hash(self.hash_type)
+            .is_some_and(|hash| self.fingerprints.iter().any(|f| f[..] == *hash));
+        let res = if pinned {
+            Ok(())
+        } else {
+            Err(HostKeyError::new(session, host, port, HostKeyErrorKind::NotPinned).into())
+        };
+        Box::pin(future::ready(res))
+    }
+}
+
+/// Accepts every host key without checking it.
+///
+/// This makes the connection vulnerable to man-in-the-middle attacks and
+/// should only be used for tests or on fully trusted networks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InsecureAcceptAll;
+
+impl HostKeyVerifier for InsecureAcceptAll {
+    fn verify<'a>(
+        &'a self,
+        _session: &'a Session,
+        _host: &'a str,
+        _port: u16,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(future::ready(Ok(())))
+    }
+}
@@ -0,0 +1,416 @@
+use crate::{
+    util::{base64_decode, base64_encode, unblock, wildcard_match},
+    Error,
+};
+use hmac::{Hmac, KeyInit, Mac};
+use sha1::Sha1;
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Marker in front of a `known_hosts` entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KnownHostMarker {
+    /// `@cert-authority`: the key is a CA trusted to sign host certificates.
+    /// Kept in the file, but ignored by [`KnownHostsFile::check`].
+    CertAuthority,
+    /// `@revoked`: the key must never be accepted.
+    Revoked,
+}
+
+/// Result of [`KnownHostsFile::check`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KnownHostStatus {
+    /// The host is listed with this key.
+    Match,
+    /// The host is listed with a different key of the same type.
+    Mismatch,
+    /// The key is marked as `@revoked` for this host.
+    Revoked,
+    /// The host is not listed with a key of this type.
+    NotFound,
+}
+
+/// A single entry of a `known_hosts` file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KnownHostEntry {
+    marker: Option<KnownHostMarker>,
+    hosts: String,
+    key_type: String,
+    key: Vec<u8>,
+    comment: Option<String>,
+}
+
+impl KnownHostEntry {
+    /// The `@cert-authority` or `@revoked` marker, if any.
+    pub fn marker(&self) -> Option<KnownHostMarker> {
+        self.marker
+    }
+
+    /// The host field as written in the file: a comma separated list of
+    /// patterns or a single `|1|salt|hash` hashed name.
+    pub fn hosts(&self) -> &str {
+        &self.hosts
+    }
+
+    /// Whether the host name is hashed (`HashKnownHosts yes`).
+    pub fn is_hashed(&self) -> bool {
+        self.hosts.starts_with("|1|")
+    }
+
+    /// The key type, e.g. `ssh-ed25519`.
+    pub fn key_type(&self) -> &str {
+        &self.key_type
+    }
+
+    /// The raw key blob, in the same format as [`Session::host_key`](crate::Session::host_key).
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The comment after the key, if any.
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    /// Whether this entry applies to `host` connected to on `port`.
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        let name = host_name(host, port);
+        if self.is_hashed() {
+            return hashed_matches(&self.hosts, &name);
+        }
+        let mut matched = false;
+        for pattern in self.hosts.split(',') {
+            let (negated, pattern) = match pattern.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, pattern),
+            };
+            if wildcard_match(&pattern.to_lowercase(), &name) {
+                if negated {
+                    return false;
+                }
+                matched = true;
+            }
+        }
+        matched
+    }
+
+    fn parse(line: &str) -> Option<KnownHostEntry> {
+        let mut fields = line.split_whitespace();
+        let mut hosts = fields.next()?;
+        let marker = match hosts {
+            "@cert-authority" => Some(KnownHostMarker::CertAuthority),
+            "@revoked" => Some(KnownHostMarker::Revoked),
+            _ if hosts.starts_with('@') => return None,
+            _ => None,
+        };
+        if marker.is_some() {
+            hosts = fields.next()?;
+        }
+        let key_type = fields.next()?;
+        let key = base64_decode(fields.next()?)?;
+        let comment = fields.collect::<Vec<_>>().join(" ");
+        Some(KnownHostEntry {
+            marker,
+            hosts: hosts.to_owned(),
+            key_type: key_type.to_owned(),
+            key,
+            comment: if comment.is_empty() { None } else { Some(comment) },
+        })
+    }
+
+    fn to_line(&self) -> String {
+        let mut line = String::new();
+        match self.marker {
+            Some(KnownHostMarker::CertAuthority) => line.push_str("@cert-authority "),
+            Some(KnownHostMarker::Revoked) => line.push_str("@revoked "),
+            None => {}
+        }
+        line.push_str(&self.hosts);
+        line.push(' ');
+        line.push_str(&self.key_type);
+        line.push(' ');
+        line.push_str(&base64_encode(&self.key));
+        if let Some(comment) = &self.comment {
+            line.push(' ');
+            line.push_str(comment);
+        }
+        line
+    }
+}
+
+/// How OpenSSH names a host in `known_hosts`: `host` on the default port,
+/// `[host]:port` otherwise.
+fn host_name(host: &str, port: u16) -> String {
+    let host = host.to_lowercase();
+    if port == 22 {
+        host
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+fn hashed_matches(hosts: &str, name: &str) -> bool {
+    let mut parts = hosts["|1|".len()..].splitn(2, '|').map(base64_decode);
+    let (salt, hash) = match (parts.next(), parts.next()) {
+        (Some(Some(salt)), Some(Some(hash))) => (salt, hash),
+        _ => return false,
+    };
+    let mut mac = <Hmac<Sha1> as KeyInit>::new_from_slice(&salt).unwrap();
+    mac.update(name.as_bytes());
+    mac.verify_slice(&hash).is_ok()
+}
+
+fn hash_host_name(name: &str) -> io::Result<String> {
+    let mut salt = [0; 20];
+    getrandom::getrandom(&mut salt)?;
+    let mut mac = <Hmac<Sha1> as KeyInit>::new_from_slice(&salt).unwrap();
+    mac.update(name.as_bytes());
+    let hash = mac.finalize().into_bytes();
+    Ok(format!("|1|{}|{}", base64_encode(&salt), base64_encode(&hash)))
+}
+
+/// The key type name embedded at the start of a key blob.
+pub(crate) fn key_type_name(key: &[u8]) -> Option<&str> {
+    let len = u32::from_be_bytes([*key.first()?, *key.get(1)?, *key.get(2)?, *key.get(3)?]);
+    let name = key.get(4..4usize.checked_add(len as usize)?)?;
+    std::str::from_utf8(name).ok()
+}
+
+#[derive(Clone, Debug)]
+enum Line {
+    Entry(KnownHostEntry, Option<String>),
+    Other(String),
+}
+
+/// An OpenSSH `known_hosts` file loaded into memory.
+///
+/// Unlike [`KnownHosts`](ssh2::KnownHosts) this understands `@revoked` and
+/// `@cert-authority` markers, and all file IO happens off the executor.
+/// Comments, blank lines and entries that could not be parsed are written
+/// back unchanged.
+///
+/// ```rust,no_run
+/// use async_ssh2::{KnownHostStatus, KnownHostsFile};
+///
+/// # async fn run(key: &[u8]) -> Result<(), async_ssh2::Error> {
+/// let mut known_hosts = KnownHostsFile::open("/home/user/.ssh/known_hosts").await?;
+/// if known_hosts.check("example.com", 22, key) != KnownHostStatus::Match {
+///     known_hosts.replace("example.com", 22, key, true)?;
+///     known_hosts.save().await?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct KnownHostsFile {
+    path: PathBuf,
+    lines: Vec<Line>,
+}
+
+impl KnownHostsFile {
+    /// Reads the file at `path`. A missing file is treated as empty.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<KnownHostsFile, Error> {
+        let path = path.as_ref().to_owned();
+        let read_path = path.clone();
+        let contents = unblock(move || match fs::read(&read_path) {
+            Ok(contents) => Ok(String::from_utf8_lossy(&contents).into_owned()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            Err(e) => Err(e),
+        })
+        .await?;
+        let lines = contents
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim();
+                if trimmed.is_empty() || trimmed.starts_with('#') {
+                    return Line::Other(line.to_owned());
+                }
+                match KnownHostEntry::parse(trimmed) {
+                    Some(entry) => Line::Entry(entry, Some(line.to_owned())),
+                    None => Line::Other(line.to_owned()),
+                }
+            })
+            .collect();
+        Ok(KnownHostsFile { path, lines })
+    }
+
+    /// The path the file was read from and is saved to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// All parsed entries, in file order.
+    pub fn entries(&self) -> impl Iterator<Item = &KnownHostEntry> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Entry(entry, _) => Some(entry),
+            Line::Other(_) => None,
+        })
+    }
+
+    /// Looks up the key a server at `host:port` presented.
+    ///
+    /// `@cert-authority` entries are skipped: libssh2 does not support host
+    /// certificates, so a server's key never matches one.
+    pub fn check(&self, host: &str, port: u16, key: &[u8]) -> KnownHostStatus {
+        let key_type = key_type_name(key);
+        let mut status = KnownHostStatus::NotFound;
+        for entry in self.entries().filter(|e| e.matches(host, port)) {
+            match entry.marker {
+                Some(KnownHostMarker::Revoked) if entry.key == key => {
+                    return KnownHostStatus::Revoked
+                }
+                Some(_) => {}
+                None if entry.key == key => status = KnownHostStatus::Match,
+                None if Some(entry.key_type.as_str()) == key_type
+                    && status == KnownHostStatus::NotFound =>
+                {
+                    status = KnownHostStatus::Mismatch
+                }
+                None => {}
+            }
+        }
+        status
+    }
+
+    /// Adds an entry for `host:port`, hashing the host name if `hash` is set.
+    /// Call [`save`](KnownHostsFile::save) to write it to disk.
+    pub fn add(&mut self, host: &str, port: u16, key: &[u8], hash: bool) -> Result<(), Error> {
+        let key_type = key_type_name(key).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "malformed host key")
+        })?;
+        let name = host_name(host, port);
+        let hosts = if hash { hash_host_name(&name)? } else { name };
+        let entry = KnownHostEntry {
+            marker: None,
+            hosts,
+            key_type: key_type.to_owned(),
+            key: key.to_vec(),
+            comment: None,
+        };
+        self.lines.push(Line::Entry(entry, None));
+        Ok(())
+    }
+
+    /// Adds an entry like [`add`](KnownHostsFile::add) and appends it to the
+    /// file on disk right away, leaving the rest of it alone.
+    ///
+    /// Unlike [`save`](KnownHostsFile::save), this keeps entries other
+    /// processes or tasks added since the file was opened, like OpenSSH does
+    /// when it learns a new host.
+    pub async fn append(&mut self, host: &str, port: u16, key: &[u8], hash: bool) -> Result<(), Error> {
+        self.add(host, port, key, hash)?;
+        let line = match self.lines.last_mut() {
+            Some(Line::Entry(entry, raw)) => raw.insert(entry.to_line()).clone(),
+            _ => unreachable!("add pushes an entry"),
+        };
+        let path = self.path.clone();
+        unblock(move || append_line(&path, &line)).await?;
+        Ok(())
+    }
+
+    /// Removes the entries for `host:port` with a key of the same type as
+    /// `key` and adds `key` instead. Lines listing several hosts are removed
+    /// as a whole. Call [`save`](KnownHostsFile::save) to write it to disk.
+    pub fn replace(&mut self, host: &str, port: u16, key: &[u8], hash: bool) -> Result<(), Error> {
+        let key_type = key_type_name(key).map(str::to_owned);
+        self.lines.retain(|line| match line {
+            Line::Entry(entry, _) => {
+                entry.marker.is_some()
+                    || Some(&entry.key_type) != key_type.as_ref()
+                    || !entry.matches(host, port)
+            }
+            Line::Other(_) => true,
+        });
+        self.add(host, port, key, hash)
+    }
+
+    /// Removes all unmarked entries for `host:port` and returns how many
+    /// were removed.
+    pub fn remove(&mut self, host: &str, port: u16) -> usize {
+        let before = self.lines.len();
+        self.lines.retain(|line| match line {
+            Line::Entry(entry, _) => entry.marker.is_some() || !entry.matches(host, port),
+            Line::Other(_) => true,
+        });
+        before - self.lines.len()
+    }
+
+    /// Writes the file back to [`path`](KnownHostsFile::path).
+    ///
+    /// The contents are written to a temporary file next to it which is then
+    /// renamed over the original, so readers never see a partial file.
+    pub async fn save(&self) -> Result<(), Error> {
+        let mut contents = String::new();
+        for line in &self.lines {
+            match line {
+                Line::Entry(_, Some(raw)) | Line::Other(raw) => contents.push_str(raw),
+                Line::Entry(entry, None) => contents.push_str(&entry.to_line()),
+            }
+            contents.push('\n');
+        }
+        let path = self.path.clone();
+        unblock(move || write_atomic(&path, contents.as_bytes())).await?;
+        Ok(())
+    }
+}
+
+/// Appends `line` to the file at `path` with a single write, so that
+/// concurrent appends don't interleave.
+fn append_line(path: &Path, line: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+    let mut contents = String::new();
+    // Don't glue the entry onto a last line that lacks its newline.
+    let len = file.metadata()?.len();
+    if len > 0 {
+        let mut last = [0];
+        file.seek(SeekFrom::Start(len - 1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            contents.push('\n');
+        }
+    }
+    contents.push_str(line);
+    contents.push('\n');
+    file.write_all(contents.as_bytes())
+}
+
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    // Concurrent saves, even from the same process, each get their own file.
+    let mut suffix = [0; 8];
+    getrandom::getrandom(&mut suffix)?;
+    let suffix = u64::from_ne_bytes(suffix);
+    let tmp = dir.join(format!(".{}.{}.{:016x}.tmp", file_name, process::id(), suffix));
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)?;
+    let res = (|| {
+        if let Ok(metadata) = fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
@@ -2,20 +2,42 @@ mod util;
 
 mod agent;
 mod channel;
+mod connect;
 mod error;
+mod forward;
+mod host_key;
+mod known_hosts;
 mod listener;
+mod process;
 mod session;
 mod sftp;
+mod socks;
+mod ssh_config;
+mod timeout;
+mod transport;
 
 pub use agent::Agent;
-pub use channel::Channel;
-pub use error::Error;
+pub use channel::{Channel, ChannelStream};
+pub use connect::ConnectOptions;
+pub use error::{
+    ConnectError, Error, ErrorKind, HostKeyError, HostKeyErrorKind, OperationError,
+    ProxyCommandError,
+};
+pub use forward::{
+    DynamicForward, ForwardHandle, ForwardTarget, LocalForward, RemoteForward, Reports,
+    TunnelReport, TunnelStats,
+};
+pub use host_key::{FingerprintVerifier, HostKeyVerifier, InsecureAcceptAll, KnownHostsVerifier};
+pub use known_hosts::{KnownHostEntry, KnownHostMarker, KnownHostStatus, KnownHostsFile};
 pub use listener::Listener;
-pub use session::Session;
+pub use process::{ExitStatus, Output, RemoteChild, RemoteCommand};
+pub use session::{AsyncKeyboardInteractivePrompt, Session};
 pub use sftp::{File, Sftp};
+pub use ssh_config::{HostConfig, SshConfig, StrictHostKeyChecking};
+pub use timeout::{with_timeout, WithTimeout};
 
 pub use ssh2::{
-    BlockDirections, ExitSignal, FileStat, FileType, Host, KnownHostFileKind, KnownHosts,
-    OpenFlags, Prompt, PtyModes, PublicKey, ReadWindow, RenameFlags, ScpFileStat, WriteWindow, 
-    TraceFlags
+    BlockDirections, ExitSignal, FileStat, FileType, HashType, Host, HostKeyType,
+    KeyboardInteractivePrompt, KnownHostFileKind, KnownHosts, MethodType, OpenFlags, Prompt,
+    PtyModes, PublicKey, ReadWindow, RenameFlags, ScpFileStat, TraceFlags, WriteWindow,
 };
@@ -1,17 +1,20 @@
-use crate::{channel::Channel, util::run_ssh2_fn, Error};
-use async_io::Async;
-use ssh2::{self};
-use std::{net::TcpStream, sync::Arc};
+use crate::{channel::Channel, transport::Transport, util::{run_ssh2_fn, would_block, Op}, Error};
+use ssh2::{self, BlockDirections};
+use std::{
+    io,
+    sync::Arc,
+    task::{Context, Poll},
+};
 
 /// See [`Listener`](ssh2::Listener).
 pub struct Listener {
     inner: ssh2::Listener,
     inner_session: ssh2::Session,
-    stream: Arc<Async<TcpStream>>,
+    stream: Arc<Transport>,
 }
 
 impl Listener {
-    pub(crate) fn new(listener: ssh2::Listener, session: ssh2::Session, stream: Arc<Async<TcpStream>>) -> Listener {
+    pub(crate) fn new(listener: ssh2::Listener, session: ssh2::Session, stream: Arc<Transport>) -> Listener {
         Listener {
             inner: listener,
             inner_session: session,
@@ -22,7 +25,63 @@ impl Listener {
     /// See [`accept`](ssh2::Listener::accept).
     pub async fn accept(&mut self) -> Result<Channel, Error> {
         let inner = &mut self.inner;
-        let channel = run_ssh2_fn(&self.stream.clone(), &self.inner_session, || inner.accept()).await?;
+        let op = Op::new("accept").abandonable();
+        let channel = run_ssh2_fn(&self.stream.clone(), &self.inner_session, op, || inner.accept()).await?;
         Ok(Channel::new(channel, self.inner_session.clone(), self.stream.clone()))
     }
+
+    /// Polls for a connection. Unlike [`accept`](Listener::accept) this waits
+    /// indefinitely, as forwards are idle for long.
+    pub(crate) fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Result<Channel, Error>> {
+        let op = Op::new("accept");
+        let mut retried = false;
+        loop {
+            let inner = &mut self.inner;
+            let lock = futures::ready!(self.stream.poll_lock(cx));
+            let (result, directions) = lock.call(&self.inner_session, || inner.accept());
+            match result {
+                Ok(channel) => {
+                    // Accepting confirms the channel to the server.
+                    if let Poll::Ready(Err(e)) = self.stream.poll_flush(cx) {
+                        return Poll::Ready(Err(op.error(&self.stream, e.into())));
+                    }
+                    let channel = Channel::new(channel, self.inner_session.clone(), self.stream.clone());
+                    return Poll::Ready(Ok(channel));
+                }
+                Err(e) if would_block(&e) => {
+                    if let Err(e) = futures::ready!(self.stream.poll_wait(cx, &directions, &mut retried)) {
+                        return Poll::Ready(Err(op.error(&self.stream, e.into())));
+                    }
+                }
+                Err(e) => return Poll::Ready(Err(op.error(&self.stream, e.into()))),
+            }
+        }
+    }
+
+    /// Asks the server to stop listening with a `cancel-tcpip-forward`
+    /// request and drops the connections that were not accepted yet.
+    ///
+    /// Dropping the listener does the same, but gives up if the socket cannot
+    /// take the request right away, which leaves the session unable to send
+    /// anything else.
+    pub async fn cancel(self) -> Result<(), Error> {
+        let Listener {
+            inner,
+            inner_session,
+            stream,
+        } = self;
+        let op = Op::new("channel_forward_cancel");
+        // ssh2 only sends the request when the listener is freed, and frees
+        // it without retrying, so make sure there is room for it first.
+        if let Err(e) = stream.wait(&BlockDirections::Outbound, &mut false).await {
+            return Err(op.error(&stream, e));
+        }
+        let ((), directions) = stream.lock().await.call(&inner_session, || drop(inner));
+        if let BlockDirections::Outbound | BlockDirections::Both = directions {
+            stream.mark_dead();
+            let e = io::Error::other("the request could only be sent in part");
+            return Err(op.error(&stream, e.into()));
+        }
+        stream.flush().await.map_err(|e| op.error(&stream, e.into()))
+    }
 }
@@ -0,0 +1,383 @@
+use crate::{
+    channel::{Channel, ChannelStream},
+    util::Op,
+    Error, ErrorKind, Session,
+};
+use futures::{future, io::AsyncRead};
+use std::{
+    borrow::Cow,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The output of a finished remote command, see
+/// [`Session::exec`](crate::Session::exec).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    /// The exit status reported by the server. libssh2 reports 0 if there
+    /// was none, e.g. because the command was killed by a signal.
+    pub status: i32,
+    /// The name of the signal that killed the command, without the `SIG`
+    /// prefix.
+    pub signal: Option<String>,
+    /// Everything the command wrote to its standard output.
+    pub stdout: Vec<u8>,
+    /// Everything the command wrote to its standard error.
+    pub stderr: Vec<u8>,
+}
+
+impl Output {
+    /// Whether the command exited with status 0 rather than being killed.
+    pub fn success(&self) -> bool {
+        self.status == 0 && self.signal.is_none()
+    }
+}
+
+/// How a remote command finished, see [`RemoteChild::wait`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitStatus {
+    code: i32,
+    signal: Option<String>,
+}
+
+impl ExitStatus {
+    /// Whether the command exited with status 0 rather than being killed.
+    pub fn success(&self) -> bool {
+        self.code == 0 && self.signal.is_none()
+    }
+
+    /// The exit status reported by the server, 0 if there was none.
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
+    /// The name of the signal that killed the command, without the `SIG`
+    /// prefix.
+    pub fn signal(&self) -> Option<&str> {
+        self.signal.as_deref()
+    }
+}
+
+/// A builder for a command run on the server, in the spirit of
+/// [`std::process::Command`].
+///
+/// The program and its arguments are quoted for a POSIX shell, so they reach
+/// the command exactly as given.
+///
+/// ```rust,no_run
+/// # async fn run(sess: &async_ssh2::Session) -> Result<(), async_ssh2::Error> {
+/// let output = sess
+///     .command("ls")
+///     .arg("-l")
+///     .arg("my documents")
+///     .env("LC_ALL", "C")
+///     .current_dir("/home/me")
+///     .output()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct RemoteCommand {
+    session: Session,
+    program: String,
+    args: Vec<String>,
+    envs: Vec<(String, String)>,
+    current_dir: Option<String>,
+    pty: Option<String>,
+}
+
+impl RemoteCommand {
+    /// Starts building a command that runs `program` on `session`.
+    pub fn new(session: &Session, program: &str) -> RemoteCommand {
+        RemoteCommand {
+            session: session.clone(),
+            program: program.to_owned(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            current_dir: None,
+            pty: None,
+        }
+    }
+
+    /// Adds an argument.
+    pub fn arg(&mut self, arg: &str) -> &mut RemoteCommand {
+        self.args.push(arg.to_owned());
+        self
+    }
+
+    /// Adds several arguments.
+    pub fn args<I, S>(&mut self, args: I) -> &mut RemoteCommand
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.args
+            .extend(args.into_iter().map(|arg| arg.as_ref().to_owned()));
+        self
+    }
+
+    /// Sets an environment variable.
+    ///
+    /// The variable is passed with [`Channel::setenv`]. Servers only accept
+    /// the variables they are configured to (`AcceptEnv` in OpenSSH); the
+    /// others are exported by the remote shell before it runs the command.
+    /// Names other than
+    /// letters, digits and `_`, not starting with a digit, make the command
+    /// fail to start.
+    pub fn env(&mut self, key: &str, val: &str) -> &mut RemoteCommand {
+        self.envs.push((key.to_owned(), val.to_owned()));
+        self
+    }
+
+    /// Sets several environment variables, see [`env`](RemoteCommand::env).
+    pub fn envs<I, K, V>(&mut self, vars: I) -> &mut RemoteCommand
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        for (key, val) in vars {
+            self.env(key.as_ref(), val.as_ref());
+        }
+        self
+    }
+
+    /// Runs the command in `dir` instead of the user's home directory.
+    pub fn current_dir(&mut self, dir: &str) -> &mut RemoteCommand {
+        self.current_dir = Some(dir.to_owned());
+        self
+    }
+
+    /// Runs the command on a pseudo-terminal of type `term`, e.g. `xterm`.
+    ///
+    /// The terminal merges stderr into stdout.
+    pub fn pty(&mut self, term: &str) -> &mut RemoteCommand {
+        self.pty = Some(term.to_owned());
+        self
+    }
+
+    /// Starts the command, returning handles on its stdin, stdout and stderr.
+    pub async fn spawn(&mut self) -> Result<RemoteChild, Error> {
+        let (mut channel, command) = self.prepare().await?;
+        channel.exec(&command).await?;
+        Ok(RemoteChild {
+            stdin: Some(channel.stream_handle(0)),
+            stdout: Some(channel.stream_handle(0)),
+            stderr: Some(channel.stderr_handle()),
+            channel,
+        })
+    }
+
+    /// Runs the command with its stdin closed and collects its output, see
+    /// [`Session::exec`].
+    pub async fn output(&mut self) -> Result<Output, Error> {
+        let (mut channel, command) = self.prepare().await?;
+        output(&mut channel, &command, None).await
+    }
+
+    /// Opens the channel, sets up its environment and terminal and returns
+    /// it with the command line to run.
+    async fn prepare(&self) -> Result<(Channel, String), Error> {
+        if let Some((key, _)) = self.envs.iter().find(|(key, _)| !is_env_name(key)) {
+            let message = format!("invalid environment variable name `{}`", key);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+        }
+        let mut channel = self.session.channel_session().await?;
+        if let Some(term) = &self.pty {
+            channel.request_pty(term, None, None).await?;
+        }
+        let mut rejected = Vec::new();
+        for (key, val) in &self.envs {
+            match channel.setenv(key, val).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::RequestDenied => rejected.push((key, val)),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut command = String::new();
+        if let Some(dir) = &self.current_dir {
+            command.push_str(&format!("cd {} && ", quote(dir)));
+        }
+        // Not through `env`, which would take a program named like
+        // `FOO=bar` for another variable.
+        for (key, val) in rejected {
+            command.push_str(&format!("export {}={} && ", key, quote(val)));
+        }
+        if !command.is_empty() {
+            command.push_str("exec ");
+        }
+        command.push_str(&quote(&self.program));
+        for arg in &self.args {
+            command.push(' ');
+            command.push_str(&quote(arg));
+        }
+        Ok((channel, command))
+    }
+}
+
+/// Whether `name` can be set as a variable by a POSIX shell or `env`.
+fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Quotes `arg` for a POSIX shell, leaving it alone if that is not needed.
+///
+/// `=` is quoted too, as a word like `FOO=bar` in front of the command would
+/// be taken for an assignment.
+fn quote(arg: &str) -> Cow<'_, str> {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-./:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        Cow::Borrowed(arg)
+    } else {
+        Cow::Owned(format!("'{}'", arg.replace('\'', r"'\''")))
+    }
+}
+
+/// A command started with [`RemoteCommand::spawn`].
+///
+/// The handles can be taken out and driven from different tasks. Like with
+/// local processes, a command blocks once it has written more output than
+/// the channel window holds, so stdout and stderr have to be read while
+/// waiting for it.
+pub struct RemoteChild {
+    channel: Channel,
+    /// The command's stdin. Closing it sends EOF.
+    pub stdin: Option<ChannelStream>,
+    /// The command's stdout.
+    pub stdout: Option<ChannelStream>,
+    /// The command's stderr, empty if it runs on a pseudo-terminal.
+    pub stderr: Option<ChannelStream>,
+}
+
+impl RemoteChild {
+    /// Waits for the command to finish.
+    ///
+    /// If the child still holds stdin, it is closed first. Output on the
+    /// stdout and stderr handles it still holds is discarded.
+    pub async fn wait(&mut self) -> Result<ExitStatus, Error> {
+        if self.stdin.take().is_some() {
+            self.channel.send_eof().await?;
+        }
+        let drain = |stream: Option<ChannelStream>| async move {
+            match stream {
+                Some(stream) => futures::io::copy(stream, &mut futures::io::sink()).await,
+                None => Ok(0),
+            }
+        };
+        future::try_join(drain(self.stdout.take()), drain(self.stderr.take())).await?;
+
+        self.channel.wait_eof().await?;
+        self.channel.wait_close().await?;
+        Ok(ExitStatus {
+            code: self.channel.exit_status()?,
+            signal: self.channel.exit_signal()?.exit_signal,
+        })
+    }
+
+    /// Sends the command the signal `name`, without the `SIG` prefix.
+    ///
+    /// OpenSSH only delivers signals since version 7.9, and only to commands
+    /// that are not forced by the server.
+    pub async fn signal(&mut self, name: &str) -> Result<(), Error> {
+        self.channel.process_startup("signal", Some(name)).await
+    }
+
+    /// Kills the command with `SIGKILL`, or closes its channel if the server
+    /// does not deliver signals. Closing makes the server hang up the
+    /// terminal of a command running on one.
+    pub async fn kill(&mut self) -> Result<(), Error> {
+        match self.signal("KILL").await {
+            Err(e) if e.kind() == ErrorKind::RequestDenied => self.channel.close().await,
+            result => result,
+        }
+    }
+}
+
+/// One of the two output streams being collected.
+struct Collector {
+    stream: ChannelStream,
+    data: Vec<u8>,
+    done: bool,
+}
+
+impl Collector {
+    fn new(stream: ChannelStream) -> Collector {
+        Collector {
+            stream,
+            data: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// Reads what is available, returning whether any progress was made.
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+        max_output: Option<usize>,
+    ) -> io::Result<bool> {
+        if self.done {
+            return Ok(false);
+        }
+        match Pin::new(&mut self.stream).poll_read(cx, buf) {
+            Poll::Ready(Ok(0)) => self.done = true,
+            Poll::Ready(Ok(n)) => {
+                self.data.extend_from_slice(&buf[..n]);
+                if let Some(max) = max_output.filter(|&max| self.data.len() > max) {
+                    return Err(io::Error::other(format!(
+                        "command wrote more than {} bytes to {}",
+                        max,
+                        if self.stream.id() == 0 { "stdout" } else { "stderr" }
+                    )));
+                }
+            }
+            Poll::Ready(Err(e)) => return Err(e),
+            Poll::Pending => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// Runs `command` on `channel` and collects its output.
+///
+/// Both streams are read at the same time: a command that fills the channel
+/// window with stderr would otherwise never get to finish its stdout.
+pub(crate) async fn output(
+    channel: &mut Channel,
+    command: &str,
+    max_output: Option<usize>,
+) -> Result<Output, Error> {
+    channel.exec(command).await?;
+    channel.send_eof().await?;
+
+    let mut stdout = Collector::new(channel.stream_handle(0));
+    let mut stderr = Collector::new(channel.stderr_handle());
+    let mut buf = vec![0; 32 * 1024];
+    let collected: io::Result<()> = future::poll_fn(|cx| loop {
+        let progress = stdout.poll(cx, &mut buf, max_output)?
+            | stderr.poll(cx, &mut buf, max_output)?;
+        if stdout.done && stderr.done {
+            return Poll::Ready(Ok(()));
+        }
+        if !progress {
+            return Poll::Pending;
+        }
+    })
+    .await;
+    if let Err(e) = collected {
+        return Err(channel.error(Op::new("exec").arg(command), Error::from(e)));
+    }
+
+    channel.wait_close().await?;
+    Ok(Output {
+        status: channel.exit_status()?,
+        signal: channel.exit_signal()?.exit_signal,
+        stdout: stdout.data,
+        stderr: stderr.data,
+    })
+}
@@ -1,10 +1,27 @@
 use crate::{
-    agent::Agent, channel::Channel, listener::Listener, sftp::Sftp, util::run_ssh2_fn, Error,
+    agent::Agent,
+    channel::Channel,
+    connect::{connect_tcp, ConnectOptions},
+    host_key::HostKeyVerifier,
+    listener::Listener,
+    process::{self, Output, RemoteCommand},
+    sftp::Sftp,
+    ssh_config::{HostConfig, SshConfig},
+    transport::{self, dead_error, Transport},
+    util::{host_port, limit, local_user, run_ssh2_fn, unblock, wait_for_session, would_block, Op},
+    Error, ProxyCommandError,
+};
+use async_io::{Async, Timer};
+use futures::{
+    channel::{mpsc, oneshot},
+    executor,
+    future::{self, BoxFuture, Either, Future},
+    io::{AsyncRead, AsyncWrite},
+    SinkExt, StreamExt,
 };
-use async_io::Async;
 use ssh2::{
     self, DisconnectCode, HashType, HostKeyType, KeyboardInteractivePrompt, KnownHosts, MethodType,
-    ScpFileStat, BlockDirections
+    Prompt, ScpFileStat, BlockDirections
 };
 #[cfg(unix)]
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -12,16 +29,148 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::windows::io::{AsRawSocket, RawSocket};
 use std::{
     convert::From,
+    io,
     net::TcpStream,
     path::Path,
+    process::Command,
     sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 
+/// The longest socket path [`Session::channel_direct_streamlocal`] can
+/// send.
+const STREAMLOCAL_PATH_MAX: usize = 0x7f;
+
+/// How many `ProxyJump` hosts may themselves be reached through a
+/// `ProxyJump`, to stop configurations that loop.
+const MAX_PROXY_JUMP_DEPTH: usize = 8;
+
+/// Async counterpart of [`KeyboardInteractivePrompt`](ssh2::KeyboardInteractivePrompt).
+///
+/// The prompter is asked once per round of challenges sent by the server and
+/// may await anything (a user, a vault, a TOTP generator, ...) before
+/// answering. Every blocking [`KeyboardInteractivePrompt`] is also an
+/// `AsyncKeyboardInteractivePrompt`.
+pub trait AsyncKeyboardInteractivePrompt: Send {
+    /// See [`prompt`](ssh2::KeyboardInteractivePrompt::prompt).
+    fn prompt<'a>(
+        &'a mut self,
+        username: &'a str,
+        instructions: &'a str,
+        prompts: &'a [Prompt<'a>],
+    ) -> BoxFuture<'a, Vec<String>>;
+}
+
+impl<P: KeyboardInteractivePrompt + Send> AsyncKeyboardInteractivePrompt for P {
+    fn prompt<'a>(
+        &'a mut self,
+        username: &'a str,
+        instructions: &'a str,
+        prompts: &'a [Prompt<'a>],
+    ) -> BoxFuture<'a, Vec<String>> {
+        let responses = KeyboardInteractivePrompt::prompt(self, username, instructions, prompts);
+        Box::pin(future::ready(responses))
+    }
+}
+
+/// A single round of challenges forwarded from the libssh2 callback.
+struct PromptRequest {
+    username: String,
+    instructions: String,
+    prompts: Vec<Prompt<'static>>,
+    responses: oneshot::Sender<Vec<String>>,
+}
+
+/// What the keyboard-interactive helper thread needs from the calling task.
+enum Attempt {
+    /// A round of challenges to answer.
+    Prompt(PromptRequest),
+    /// The session would block; the thread retries once it is signalled.
+    Blocked(BlockDirections, oneshot::Sender<()>),
+    /// The authentication is over.
+    Done(Result<(), ssh2::Error>),
+}
+
+/// Blocking prompter handed to libssh2 which forwards every round of
+/// challenges to the async side and waits for the answers.
+struct PromptForwarder {
+    attempts: mpsc::Sender<Attempt>,
+}
+
+impl PromptForwarder {
+    /// Hands `attempt` to the calling task, returning whether it is still
+    /// there.
+    fn send(&mut self, attempt: Attempt) -> bool {
+        executor::block_on(self.attempts.send(attempt)).is_ok()
+    }
+}
+
+impl KeyboardInteractivePrompt for PromptForwarder {
+    fn prompt<'a>(
+        &mut self,
+        username: &str,
+        instructions: &str,
+        prompts: &[Prompt<'a>],
+    ) -> Vec<String> {
+        let (tx, rx) = oneshot::channel();
+        let request = PromptRequest {
+            username: username.to_owned(),
+            instructions: instructions.to_owned(),
+            prompts: prompts
+                .iter()
+                .map(|p| Prompt {
+                    text: p.text.clone().into_owned().into(),
+                    echo: p.echo,
+                })
+                .collect(),
+            responses: tx,
+        };
+        if !self.send(Attempt::Prompt(request)) {
+            return Vec::new();
+        }
+        executor::block_on(rx).unwrap_or_default()
+    }
+}
+
+/// Runs a `ProxyCommand` through the shell, like OpenSSH does.
+fn shell_command(command: &str) -> Command {
+    #[cfg(unix)]
+    {
+        let mut shell = Command::new("/bin/sh");
+        shell.arg("-c").arg(format!("exec {}", command));
+        shell
+    }
+    #[cfg(windows)]
+    {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    }
+}
+
 /// See [`Session`](ssh2::Session).
+///
+/// # Cancellation
+///
+/// Dropping the future of an operation before it completes leaves libssh2
+/// halfway through it. Calling the same method with the same arguments again
+/// resumes it. Until then, other operations on the same channel, SFTP
+/// subsystem or, if a packet was only partially sent, the whole session fail
+/// with an error of kind [`ErrorKind::Interrupted`](crate::ErrorKind::Interrupted).
+/// Waiting for EOF, for a channel to close or for a forwarded connection can
+/// be canceled at any time.
+///
+/// # Sharing
+///
+/// `Session`, [`Channel`], [`Sftp`] and the handles derived from them are
+/// `Send` and `Sync`. Clones of a session and everything opened on it can be
+/// used from any number of tasks at once: each libssh2 call is made under a
+/// session-wide lock that is released before waiting on the socket.
 #[derive(Clone)]
 pub struct Session {
     inner: ssh2::Session,
-    stream: Option<Arc<Async<TcpStream>>>,
+    stream: Option<Arc<Transport>>,
 }
 
 #[cfg(unix)]
@@ -56,9 +205,202 @@ impl Session {
         })
     }
 
+    /// Resolves `host`, connects to it, performs the SSH handshake and
+    /// verifies the server's host key.
+    ///
+    /// Every resolved address is tried (see [`ConnectOptions`]) until one
+    /// accepts the connection. If none does, the returned
+    /// [`ConnectError`](crate::ConnectError) lists why each address failed.
+    /// A host key rejected by the configured
+    /// [`HostKeyVerifier`](crate::HostKeyVerifier) fails with
+    /// [`Error::HostKey`].
+    ///
+    /// ```rust,no_run
+    /// use async_ssh2::{ConnectOptions, Session};
+    /// use std::time::Duration;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let options = ConnectOptions::new().connect_timeout(Duration::from_secs(5));
+    ///     let sess = Session::connect("example.com", 22, &options).await.unwrap();
+    ///     sess.userauth_agent("user").await.unwrap();
+    /// }
+    /// ```
+    pub async fn connect(
+        host: &str,
+        port: u16,
+        options: &ConnectOptions,
+    ) -> Result<Session, Error> {
+        let stream = connect_tcp(host, port, options).await?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(stream)?;
+        session.stream.as_ref().unwrap().set_peer(host_port(host, port));
+        options.configure(&session)?;
+        session.handshake().await?;
+        session.verify_host_key(host, port, options.verifier()).await?;
+        Ok(session)
+    }
+
+    /// Connects to `host:port` through an already authenticated `jump`
+    /// session, like OpenSSH's `ProxyJump`, performs the handshake and
+    /// verifies the host key as configured in `options`.
+    ///
+    /// The new session runs over a `direct-tcpip` channel of `jump`, which
+    /// stays connected for as long as the new session is alive. Hops can be
+    /// chained by authenticating the returned session and using it as the
+    /// next jump host:
+    ///
+    /// ```rust,no_run
+    /// use async_ssh2::{ConnectOptions, Session};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     // ssh -J bastion,inner target
+    ///     let options = ConnectOptions::new();
+    ///     let bastion = Session::connect("bastion", 22, &options).await.unwrap();
+    ///     bastion.userauth_agent("user").await.unwrap();
+    ///     let inner = Session::connect_via(&bastion, "inner", 22, &options).await.unwrap();
+    ///     inner.userauth_agent("user").await.unwrap();
+    ///     let target = Session::connect_via(&inner, "target", 22, &options).await.unwrap();
+    ///     target.userauth_agent("user").await.unwrap();
+    /// }
+    /// ```
+    pub async fn connect_via(
+        jump: &Session,
+        host: &str,
+        port: u16,
+        options: &ConnectOptions,
+    ) -> Result<Session, Error> {
+        let channel = jump.channel_direct_tcpip(host, port, None).await?;
+        let mut session = Session::new()?;
+        session.set_stream(channel)?;
+        session.stream.as_ref().unwrap().set_peer(host_port(host, port));
+        options.configure(&session)?;
+        session.handshake().await?;
+        session.verify_host_key(host, port, options.verifier()).await?;
+        Ok(session)
+    }
+
+    /// Spawns `command`, like OpenSSH's `ProxyCommand`, and performs the
+    /// handshake over its stdin and stdout. `host` and `port` name the server
+    /// the command connects to and are used to verify its host key as
+    /// configured in `options`.
+    ///
+    /// The command's stderr is captured and can be read with
+    /// [`proxy_stderr`](Session::proxy_stderr); if the handshake fails it is
+    /// also attached to the returned [`ProxyCommandError`](crate::ProxyCommandError).
+    /// The process is killed and reaped once the session and everything
+    /// created from it has been dropped.
+    ///
+    /// ```rust,no_run
+    /// use async_ssh2::{ConnectOptions, Session};
+    /// use std::process::Command;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let mut nc = Command::new("nc");
+    ///     nc.args(&["target", "22"]);
+    ///     let options = ConnectOptions::new();
+    ///     let sess = Session::connect_command("target", 22, nc, &options).await.unwrap();
+    ///     sess.userauth_agent("user").await.unwrap();
+    /// }
+    /// ```
+    pub async fn connect_command(
+        host: &str,
+        port: u16,
+        command: Command,
+        options: &ConnectOptions,
+    ) -> Result<Session, Error> {
+        let program = command.get_program().to_string_lossy().into_owned();
+        let transport = Transport::command(command)
+            .map_err(|e| ProxyCommandError::new(&program, String::new(), e.into()))?;
+        transport.set_peer(host_port(host, port));
+        let mut session = Session::new()?;
+        session.set_transport(transport);
+        options.configure(&session)?;
+        if let Err(e) = session.handshake().await {
+            let transport = session.stream.as_ref().unwrap();
+            transport.settle_proxy_stderr(Duration::from_millis(100)).await;
+            let stderr = session.proxy_stderr().unwrap_or_default();
+            return Err(ProxyCommandError::new(&program, stderr, e).into());
+        }
+        session.verify_host_key(host, port, options.verifier()).await?;
+        Ok(session)
+    }
+
+    /// Connects to `host` as configured in `config`, like `ssh host` does
+    /// with the user's `~/.ssh/config`.
+    ///
+    /// `HostName` and `Port` select the server, `ProxyCommand` is run through
+    /// the shell (see [`connect_command`](Session::connect_command)) and
+    /// `ProxyJump` hops are connected to in turn (see
+    /// [`connect_via`](Session::connect_via)) and authenticated with
+    /// [`userauth_with_config`](Session::userauth_with_config). The remaining
+    /// settings are applied through [`HostConfig::connect_options`]. The
+    /// returned session is not authenticated yet.
+    ///
+    /// ```rust,no_run
+    /// use async_ssh2::{Session, SshConfig};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let config = SshConfig::read(SshConfig::user_file()).await.unwrap();
+    ///     let sess = Session::connect_with_config(&config, "prod-db").await.unwrap();
+    ///     sess.userauth_with_config(&config.resolve("prod-db")).await.unwrap();
+    /// }
+    /// ```
+    pub async fn connect_with_config(config: &SshConfig, host: &str) -> Result<Session, Error> {
+        Session::connect_host_config(config, config.resolve(host), 0).await
+    }
+
+    fn connect_host_config(
+        config: &SshConfig,
+        host: HostConfig,
+        depth: usize,
+    ) -> BoxFuture<'_, Result<Session, Error>> {
+        Box::pin(async move {
+            let options = host.connect_options();
+            let (host_name, port) = (host.host_name(), host.port());
+            if let Some(command) = host.proxy_command() {
+                let command = shell_command(&command);
+                return Session::connect_command(&host_name, port, command, &options).await;
+            }
+            let hops = match host.proxy_jump() {
+                Some(hops) => hops,
+                None => return Session::connect(&host_name, port, &options).await,
+            };
+            // The first hop may itself be configured with a `ProxyJump`.
+            if depth >= MAX_PROXY_JUMP_DEPTH {
+                let e = io::Error::new(io::ErrorKind::InvalidInput, "too many ProxyJump hops");
+                return Err(e.into());
+            }
+            let mut jump: Option<Session> = None;
+            for hop in hops.split(',') {
+                let hop = config.resolve_jump(hop)?;
+                let session = match &jump {
+                    None => Session::connect_host_config(config, hop.clone(), depth + 1).await?,
+                    Some(jump) => {
+                        let options = hop.connect_options();
+                        Session::connect_via(jump, &hop.host_name(), hop.port(), &options).await?
+                    }
+                };
+                session.userauth_with_config(&hop).await?;
+                jump = Some(session);
+            }
+            Session::connect_via(jump.as_ref().unwrap(), &host_name, port, &options).await
+        })
+    }
+
+    /// The last few kilobytes the proxy command wrote to stderr, or `None`
+    /// if the session was not created by
+    /// [`connect_command`](Session::connect_command).
+    pub fn proxy_stderr(&self) -> Option<String> {
+        self.stream.as_ref()?.proxy_stderr()
+    }
+
     /// See [`set_banner`](ssh2::Session::set_banner).
     pub async fn set_banner(&self, banner: &str) -> Result<(), Error> {
-        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, || {
+        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, Op::new("set_banner"), || {
             self.inner.set_banner(banner)
         })
         .await
@@ -80,6 +422,9 @@ impl Session {
     }
 
     /// See [`set_timeout`](ssh2::Session::set_timeout).
+    ///
+    /// This only affects libssh2's blocking mode, which the async methods
+    /// don't use; see [`set_operation_timeout`](Session::set_operation_timeout).
     pub fn set_timeout(&self, timeout_ms: u32) {
         self.inner.set_timeout(timeout_ms)
     }
@@ -89,9 +434,33 @@ impl Session {
         self.inner.timeout()
     }
 
+    /// Limits how long any async method of this session and of the channels,
+    /// SFTP handles and listeners created from it may take. `None`, the
+    /// default, waits forever. Single calls can be given a different limit
+    /// with [`with_timeout`](crate::with_timeout).
+    ///
+    /// Operations that time out fail with an error of kind
+    /// [`ErrorKind::Timeout`](crate::ErrorKind::Timeout). libssh2 cannot
+    /// abandon a request half-way, so the session is closed when that
+    /// happens: later operations fail and [`wait_dead`](Session::wait_dead)
+    /// resolves. Operations that only wait for the server are the exception
+    /// and leave the session usable: reads and writes of a [`Channel`] or
+    /// [`File`](crate::File), which time out after waiting for that long
+    /// without progress, as well as [`Channel::wait_eof`],
+    /// [`Channel::wait_close`] and [`Listener::accept`]. Writes then have to
+    /// be retried with the same data.
+    pub fn set_operation_timeout(&self, timeout: Option<Duration>) {
+        self.stream.as_ref().unwrap().set_default_timeout(timeout);
+    }
+
+    /// See [`set_operation_timeout`](Session::set_operation_timeout).
+    pub fn operation_timeout(&self) -> Option<Duration> {
+        self.stream.as_ref().unwrap().default_timeout()
+    }
+
     /// See [`handshake`](ssh2::Session::handshake).
     pub async fn handshake(&mut self) -> Result<(), Error> {
-        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, || {
+        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, Op::new("handshake"), || {
             self.inner.clone().handshake()
         })
         .await
@@ -113,35 +482,140 @@ impl Session {
     /// }
     /// ```
     pub fn set_tcp_stream(&mut self, stream: Async<TcpStream>) -> Result<(), Error> {
+        self.set_transport(Transport::tcp(stream));
+        Ok(())
+    }
+
+    /// Runs the session over any byte stream instead of a TCP socket, e.g. a
+    /// tokio `TcpStream` behind a compat layer, a TLS or websocket tunnel, or
+    /// a [`Channel`] of another session.
+    ///
+    /// libssh2 needs a real socket, so it is handed one end of a local socket
+    /// pair and the other end is bridged to `stream` whenever the session
+    /// waits for I/O. No background task is spawned, so this works with any
+    /// executor.
+    ///
+    /// ```rust,no_run
+    /// use async_ssh2::Session;
+    /// use futures::io::{AsyncRead, AsyncWrite};
+    ///
+    /// async fn over<S>(stream: S) -> Result<Session, async_ssh2::Error>
+    /// where
+    ///     S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    /// {
+    ///     let mut sess = Session::new()?;
+    ///     sess.set_stream(stream)?;
+    ///     sess.handshake().await?;
+    ///     Ok(sess)
+    /// }
+    /// ```
+    pub fn set_stream<S>(&mut self, stream: S) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        self.set_transport(Transport::bridged(stream)?);
+        Ok(())
+    }
+
+    fn set_transport(&mut self, transport: Transport) {
         #[cfg(unix)]
         {
-            let raw_fd = RawFdWrapper(stream.as_raw_fd());
+            let raw_fd = RawFdWrapper(transport.as_raw_fd());
             self.inner.set_tcp_stream(raw_fd);
+            transport::count_received(&self.inner);
         }
         #[cfg(windows)]
         {
-            let raw_socket = RawSocketWrapper(stream.as_raw_socket());
+            let raw_socket = RawSocketWrapper(transport.as_raw_socket());
             self.inner.set_tcp_stream(raw_socket);
         }
-        self.stream = Some(Arc::new(stream));
-        Ok(())
+        self.stream = Some(Arc::new(transport));
     }
 
     /// See [`userauth_password`](ssh2::Session::userauth_password).
     pub async fn userauth_password(&self, username: &str, password: &str) -> Result<(), Error> {
-        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, || {
+        let op = Op::new("userauth_password").arg(username);
+        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, op, || {
             self.inner.userauth_password(username, password)
         })
         .await
     }
 
     /// See [`userauth_keyboard_interactive`](ssh2::Session::userauth_keyboard_interactive).
-    pub fn userauth_keyboard_interactive<P: KeyboardInteractivePrompt>(
+    ///
+    /// libssh2 invokes the prompt callback synchronously, so the attempts run
+    /// on a helper thread, one per call, while the challenges are answered
+    /// by `prompter` and the socket is waited for on the calling task.
+    ///
+    /// libssh2 is in the middle of a call while `prompter` answers, so nothing
+    /// else can be done with the session until it has: other async
+    /// operations wait for the answer, and synchronous methods such as
+    /// [`authenticated`](Session::authenticated) or
+    /// [`set_keepalive`](Session::set_keepalive) block until then.
+    pub async fn userauth_keyboard_interactive<P: AsyncKeyboardInteractivePrompt>(
         &self,
-        _username: &str,
-        _prompter: &mut P,
+        username: &str,
+        prompter: &mut P,
     ) -> Result<(), Error> {
-        unimplemented!();
+        let stream = self.stream.as_ref().unwrap();
+        let op = Op::new("userauth_keyboard_interactive").arg(username);
+        let (tx, mut attempts) = mpsc::channel(0);
+        let session = self.inner.clone();
+        let transport = stream.clone();
+        let user = username.to_owned();
+        thread::spawn(move || {
+            let mut forwarder = PromptForwarder { attempts: tx };
+            loop {
+                // This holds the call lock while the prompts are answered,
+                // as ssh2 keeps the session locked for that long anyway;
+                // tasks wait for it without blocking.
+                let (result, directions) = transport.call(&session, || {
+                    session.userauth_keyboard_interactive(&user, &mut forwarder)
+                });
+                match result {
+                    Err(e) if would_block(&e) => {
+                        let (retry_tx, retry_rx) = oneshot::channel();
+                        if !forwarder.send(Attempt::Blocked(directions, retry_tx))
+                            || executor::block_on(retry_rx).is_err()
+                        {
+                            return;
+                        }
+                    }
+                    result => {
+                        forwarder.send(Attempt::Done(result));
+                        return;
+                    }
+                }
+            }
+        });
+
+        let mut retried = false;
+        while let Some(attempt) = attempts.next().await {
+            match attempt {
+                Attempt::Prompt(request) => {
+                    let responses = prompter
+                        .prompt(&request.username, &request.instructions, &request.prompts)
+                        .await;
+                    let _ = request.responses.send(responses);
+                }
+                Attempt::Blocked(directions, retry) => {
+                    limit(stream, op, wait_for_session(stream, &directions, &mut retried))
+                        .await
+                        .map_err(|e| op.error(stream, e))?;
+                    let _ = retry.send(());
+                }
+                Attempt::Done(Ok(())) => {
+                    stream.flush().await.map_err(|e| op.error(stream, e.into()))?;
+                    return Ok(());
+                }
+                Attempt::Done(Err(e)) => return Err(op.error(stream, e.into())),
+            }
+        }
+        // The thread ended without a result, so libssh2 may have been left
+        // anywhere in the exchange.
+        stream.mark_dead();
+        let e = io::Error::other("the keyboard-interactive attempt panicked");
+        Err(op.error(stream, e.into()))
     }
 
     /// See [`userauth_agent`](ssh2::Session::userauth_agent).
@@ -150,11 +624,43 @@ impl Session {
         agent.connect().await?;
         agent.list_identities()?;
         let identities = agent.identities()?;
-        let identity = match identities.get(0) {
+        let identity = match identities.first() {
             Some(identity) => identity,
-            None => return Err(Error::from(ssh2::Error::from_errno(ssh2::ErrorCode::Session(-4)))),
+            None => {
+                return Err(Error::from(ssh2::Error::new(
+                    ssh2::ErrorCode::Session(libssh2_sys::LIBSSH2_ERROR_AUTHENTICATION_FAILED),
+                    "the SSH agent has no identities",
+                )))
+            }
         };
-        agent.userauth(username, &identity).await
+        agent.userauth(username, identity).await
+    }
+
+    /// Authenticates as the `User` of `config`, or the local user, first
+    /// with the SSH agent and then with each `IdentityFile` that exists.
+    /// Keys protected by a passphrase are skipped. The error of the last
+    /// attempt is returned if none succeeds.
+    pub async fn userauth_with_config(&self, config: &HostConfig) -> Result<(), Error> {
+        let user = config.user().map(str::to_owned).unwrap_or_else(local_user);
+        let mut error = match self.userauth_agent(&user).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let identities = config.identity_files();
+        let identities = unblock(move || {
+            identities
+                .into_iter()
+                .filter(|identity| identity.is_file())
+                .collect::<Vec<_>>()
+        })
+        .await;
+        for identity in identities {
+            match self.userauth_pubkey_file(&user, None, &identity, None).await {
+                Ok(()) => return Ok(()),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
     }
 
     /// See [`userauth_pubkey_file`](ssh2::Session::userauth_pubkey_file).
@@ -165,7 +671,8 @@ impl Session {
         privatekey: &Path,
         passphrase: Option<&str>,
     ) -> Result<(), Error> {
-        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, || {
+        let op = Op::new("userauth_pubkey_file").arg(username);
+        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, op, || {
             self.inner
                 .userauth_pubkey_file(username, pubkey, privatekey, passphrase)
         })
@@ -181,7 +688,8 @@ impl Session {
         privatekeydata: &str,
         passphrase: Option<&str>,
     ) -> Result<(), Error> {
-        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, || {
+        let op = Op::new("userauth_pubkey_memory").arg(username);
+        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, op, || {
             self.inner
                 .userauth_pubkey_memory(username, pubkeydata, privatekeydata, passphrase)
         })
@@ -199,7 +707,8 @@ impl Session {
         hostname: &str,
         local_username: Option<&str>,
     ) -> Result<(), Error> {
-        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, || {
+        let op = Op::new("userauth_hostbased_file").arg(username);
+        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, op, || {
             self.inner.userauth_hostbased_file(
                 username,
                 publickey,
@@ -219,7 +728,8 @@ impl Session {
 
     /// See [`auth_methods`](ssh2::Session::auth_methods).
     pub async fn auth_methods(&self, username: &str) -> Result<&str, Error> {
-        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, || {
+        let op = Op::new("auth_methods").arg(username);
+        run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, op, || {
             self.inner.auth_methods(username)
         })
         .await
@@ -254,13 +764,46 @@ impl Session {
 
     /// See [`channel_session`](ssh2::Session::channel_session).
     pub async fn channel_session(&self) -> Result<Channel, Error> {
-        let channel = run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, || {
+        let op = Op::new("channel_session");
+        let channel = run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, op, || {
             self.inner.channel_session()
         })
         .await?;
         Ok(Channel::new(channel, self.inner.clone(), self.stream.as_ref().unwrap().clone()))
     }
 
+    /// Runs `command` on a new channel and waits for it to finish, collecting
+    /// its output like [`std::process::Command::output`].
+    ///
+    /// The command's stdin is closed right away. Use
+    /// [`exec_limited`](Session::exec_limited) if its output could be too
+    /// large to hold in memory.
+    ///
+    /// ```rust,no_run
+    /// # async fn run(sess: &async_ssh2::Session) -> Result<(), async_ssh2::Error> {
+    /// let output = sess.exec("uname -a").await?;
+    /// assert!(output.success());
+    /// println!("{}", String::from_utf8_lossy(&output.stdout));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn exec(&self, command: &str) -> Result<Output, Error> {
+        let mut channel = self.channel_session().await?;
+        process::output(&mut channel, command, None).await
+    }
+
+    /// Like [`exec`](Session::exec), but fails as soon as the command writes
+    /// more than `max_output` bytes to either stdout or stderr.
+    pub async fn exec_limited(&self, command: &str, max_output: usize) -> Result<Output, Error> {
+        let mut channel = self.channel_session().await?;
+        process::output(&mut channel, command, Some(max_output)).await
+    }
+
+    /// Starts building a command that runs `program`, see [`RemoteCommand`].
+    pub fn command(&self, program: &str) -> RemoteCommand {
+        RemoteCommand::new(self, program)
+    }
+
     /// See [`channel_direct_tcpip`](ssh2::Session::channel_direct_tcpip).
     pub async fn channel_direct_tcpip(
         &self,
@@ -268,13 +811,52 @@ impl Session {
         port: u16,
         src: Option<(&str, u16)>,
     ) -> Result<Channel, Error> {
-        let channel = run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, || {
+        let op = Op::new("channel_direct_tcpip").addr(host, port);
+        let channel = run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, op, || {
             self.inner.channel_direct_tcpip(host, port, src)
         })
         .await?;
         Ok(Channel::new(channel, self.inner.clone(), self.stream.as_ref().unwrap().clone()))
     }
 
+    /// Opens a channel to the Unix domain socket at `path` on the server,
+    /// e.g. `/var/run/docker.sock`, with OpenSSH's
+    /// `direct-streamlocal@openssh.com` extension.
+    ///
+    /// libssh2 only takes the request as a string, in which the length of
+    /// `path` has to be a single character, so `path` must be at most 127
+    /// bytes long. That is more than most systems allow for sockets; longer
+    /// paths fail with [`ErrorKind::InvalidInput`](crate::ErrorKind::InvalidInput) before anything is sent.
+    /// Listening on a socket on the server is not supported, as libssh2
+    /// drops the channels such a forward would open.
+    pub async fn channel_direct_streamlocal(&self, path: &str) -> Result<Channel, Error> {
+        let stream = self.stream.as_ref().unwrap();
+        let op = Op::new("channel_direct_streamlocal").path(Path::new(path));
+        if path.len() > STREAMLOCAL_PATH_MAX {
+            let message = format!(
+                "socket path is {} bytes long, at most {} are supported",
+                path.len(),
+                STREAMLOCAL_PATH_MAX
+            );
+            let e = io::Error::new(io::ErrorKind::InvalidInput, message);
+            return Err(op.error(stream, e.into()));
+        }
+        // string socket path, string reserved, uint32 reserved; the uint32
+        // length of the path fits in its last byte, an ASCII character.
+        let len = char::from(path.len() as u8);
+        let message = format!("\0\0\0{}{}\0\0\0\0\0\0\0\0", len, path);
+        let channel = run_ssh2_fn(stream, &self.inner, op, || {
+            self.inner.channel_open(
+                "direct-streamlocal@openssh.com",
+                libssh2_sys::LIBSSH2_CHANNEL_WINDOW_DEFAULT,
+                libssh2_sys::LIBSSH2_CHANNEL_PACKET_DEFAULT,
+                Some(&message),
+            )
+        })
+        .await?;
+        Ok(Channel::new(channel, self.inner.clone(), stream.clone()))
+    }
+
     /// See [`channel_forward_listen`](ssh2::Session::channel_forward_listen).
     pub async fn channel_forward_listen(
         &self,
@@ -282,7 +864,8 @@ impl Session {
         host: Option<&str>,
         queue_maxsize: Option<u32>,
     ) -> Result<(Listener, u16), Error> {
-        let (listener, port) = run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, || {
+        let op = Op::new("channel_forward_listen").addr(host.unwrap_or("*"), remote_port);
+        let (listener, port) = run_ssh2_fn(self.stream.as_ref().unwrap(), &self.inner, op, || {
             self.inner
                 .channel_forward_listen(remote_port, host, queue_maxsize)
         })
@@ -295,8 +878,9 @@ impl Session {
 
     /// See [`scp_recv`](ssh2::Session::scp_recv).
     pub async fn scp_recv(&self, path: &Path) -> Result<(Channel, ScpFileStat), Error> {
+        let op = Op::new("scp_recv").path(path);
         let (channel, file_stat) =
-            run_ssh2_fn(self.stream.as_ref().unwrap(),  &self.inner, || self.inner.scp_recv(path)).await?;
+            run_ssh2_fn(self.stream.as_ref().unwrap(),  &self.inner, op, || self.inner.scp_recv(path)).await?;
         Ok((
             Channel::new(channel, self.inner.clone(), self.stream.as_ref().unwrap().clone()),
             file_stat,
@@ -311,7 +895,8 @@ impl Session {
         size: u64,
         times: Option<(u64, u64)>,
     ) -> Result<Channel, Error> {
-        let channel = run_ssh2_fn(self.stream.as_ref().unwrap(),  &self.inner, || {
+        let op = Op::new("scp_send").path(remote_path);
+        let channel = run_ssh2_fn(self.stream.as_ref().unwrap(),  &self.inner, op, || {
             self.inner.scp_send(remote_path, mode, size, times)
         })
         .await?;
@@ -320,7 +905,8 @@ impl Session {
 
     /// See [`sftp`](ssh2::Session::sftp).
     pub async fn sftp(& self) -> Result<Sftp, Error> {
-        let sftp = run_ssh2_fn(self.stream.as_ref().unwrap(),  &self.inner, || self.inner.sftp()).await?;
+        let op = Op::new("sftp");
+        let sftp = run_ssh2_fn(self.stream.as_ref().unwrap(),  &self.inner, op, || self.inner.sftp()).await?;
         Ok(Sftp::new(sftp, self.inner.clone(), self.stream.as_ref().unwrap().clone()))
     }
 
@@ -332,7 +918,8 @@ impl Session {
         packet_size: u32,
         message: Option<&str>,
     ) -> Result<Channel, Error> {
-        let channel = run_ssh2_fn(self.stream.as_ref().unwrap(),  &self.inner, || {
+        let op = Op::new("channel_open").arg(channel_type);
+        let channel = run_ssh2_fn(self.stream.as_ref().unwrap(),  &self.inner, op, || {
             self.inner
                 .channel_open(channel_type, window_size, packet_size, message)
         })
@@ -360,6 +947,21 @@ impl Session {
         self.inner.host_key_hash(hash)
     }
 
+    /// Checks the host key received during the handshake with `verifier`.
+    ///
+    /// [`connect`](Session::connect) does this automatically; sessions set
+    /// up with [`set_tcp_stream`](Session::set_tcp_stream) or
+    /// [`set_stream`](Session::set_stream) should call it after
+    /// [`handshake`](Session::handshake).
+    pub async fn verify_host_key(
+        &self,
+        host: &str,
+        port: u16,
+        verifier: &dyn HostKeyVerifier,
+    ) -> Result<(), Error> {
+        verifier.verify(self, host, port).await
+    }
+
     /// See [`set_keepalive`](ssh2::Session::set_keepalive).
     pub fn set_keepalive(&self, want_reply: bool, interval: u32) {
         self.inner.set_keepalive(want_reply, interval)
@@ -367,12 +969,120 @@ impl Session {
 
     /// See [`keepalive_send`](ssh2::Session::keepalive_send).
     pub async fn keepalive_send(&self) -> Result<u32, Error> {
-        run_ssh2_fn(self.stream.as_ref().unwrap(),  &self.inner, || {
+        run_ssh2_fn(self.stream.as_ref().unwrap(),  &self.inner, Op::new("keepalive_send"), || {
             self.inner.keepalive_send()
         })
         .await
     }
 
+    /// Returns a future that keeps the connection alive and detects a server
+    /// that stopped responding, like OpenSSH's `ServerAliveInterval` and
+    /// `ServerAliveCountMax`.
+    ///
+    /// The future configures [`set_keepalive`](Session::set_keepalive) with
+    /// `interval` seconds and calls [`keepalive_send`](Session::keepalive_send)
+    /// whenever it reports a keepalive is due. If nothing arrives from the
+    /// server for `count_max` intervals in a row, the session is marked dead:
+    /// pending and later operations on it and its channels fail,
+    /// [`wait_dead`](Session::wait_dead) resolves and the future resolves
+    /// with an error. It resolves with `Ok` once the session and everything
+    /// created from it has been dropped; the future does not keep them
+    /// alive.
+    ///
+    /// Replies count whether or not the application reads them: the future
+    /// looks at everything received on the socket, read by libssh2 or not.
+    /// Leaving so much unread that the socket's receive buffer fills up
+    /// keeps replies from arriving, though.
+    ///
+    /// Nothing is spawned; run the future on any executor or drop it to
+    /// stop sending keepalives.
+    ///
+    /// ```rust,no_run
+    /// use async_ssh2::{ConnectOptions, Session};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let sess = Session::connect("example.com", 22, &ConnectOptions::new()).await.unwrap();
+    ///     tokio::spawn(sess.keepalive_task(15, 3));
+    ///     sess.wait_dead().await;
+    ///     eprintln!("server stopped responding");
+    /// }
+    /// ```
+    pub fn keepalive_task(
+        &self,
+        interval: u32,
+        count_max: u32,
+    ) -> BoxFuture<'static, Result<(), Error>> {
+        let session = self.inner.clone();
+        let stream = self.stream.as_ref().unwrap();
+        // Only a weak reference, so that dropping the transport ends the
+        // dead subscription and with it the future.
+        let weak = Arc::downgrade(stream);
+        let mut dead = stream.dead();
+        Box::pin(async move {
+            let mut sent = None;
+            let mut missed = 0;
+            loop {
+                let secs = {
+                    let transport = match weak.upgrade() {
+                        Some(transport) => transport,
+                        None => return Ok(()),
+                    };
+                    transport.pump();
+                    {
+                        let lock = transport.lock().await;
+                        lock.check_inbound()?;
+                        if sent.is_none() {
+                            // Not `set_keepalive`, which would block while a
+                            // prompt is being answered.
+                            lock.call(&session, || session.set_keepalive(true, interval));
+                        }
+                    }
+                    if let Some(sent) = sent {
+                        if transport.last_received() >= sent {
+                            missed = 0;
+                        } else {
+                            missed += 1;
+                            if missed >= count_max {
+                                transport.mark_dead();
+                                return Err(dead_error().into());
+                            }
+                        }
+                    }
+                    sent = Some(Instant::now());
+                    let op = Op::new("keepalive_send");
+                    run_ssh2_fn(&transport, &session, op, || session.keepalive_send()).await?
+                };
+                let interval = Timer::after(Duration::from_secs(u64::from(secs.max(1))));
+                match future::select(&mut dead, interval).await {
+                    Either::Left((Ok(()), _)) => return Err(dead_error().into()),
+                    // The transport has been dropped.
+                    Either::Left((Err(_), _)) => return Ok(()),
+                    Either::Right(_) => {}
+                }
+            }
+        })
+    }
+
+    /// Whether a [`keepalive_task`](Session::keepalive_task) has given up on
+    /// the server or an operation has timed out, see
+    /// [`set_operation_timeout`](Session::set_operation_timeout).
+    pub fn is_dead(&self) -> bool {
+        self.stream.as_ref().is_some_and(|stream| stream.is_dead())
+    }
+
+    /// Resolves once the session is dead, see [`is_dead`](Session::is_dead).
+    /// Any number of tasks may wait for this.
+    pub fn wait_dead(&self) -> impl Future<Output = ()> + Send + 'static {
+        let dead = self.stream.as_ref().unwrap().dead();
+        async move {
+            // The transport going away without dying is not news.
+            if dead.await.is_err() {
+                future::pending::<()>().await;
+            }
+        }
+    }
+
     /// See [`disconnect`](ssh2::Session::disconnect).
     pub async fn disconnect(
         &self,
@@ -380,7 +1090,7 @@ impl Session {
         description: &str,
         lang: Option<&str>,
     ) -> Result<(), Error> {
-        run_ssh2_fn(self.stream.as_ref().unwrap(),  &self.inner, || {
+        run_ssh2_fn(self.stream.as_ref().unwrap(),  &self.inner, Op::new("disconnect"), || {
             self.inner.disconnect(reason, description, lang)
         })
         .await
@@ -1,10 +1,12 @@
-use crate::{util::{run_ssh2_fn,poll_ssh2_io_op},Error};
+use crate::{transport::Transport, util::{run_ssh2_fn,poll_ssh2_io_op, Interrupted, Op},Error, ErrorKind};
+#[cfg(feature = "tokio")]
+use crate::util::poll_read_buf;
+use async_io::Timer;
 use futures::prelude::*;
-use async_io::Async;
-use ssh2::{self, FileStat, OpenFlags, OpenType};
+use libssh2_sys as raw;
+use ssh2::{self, ErrorCode, FileStat, OpenFlags, OpenType};
 use std::{
     io::{self, Read, Seek, Write},
-    net::TcpStream,
     path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
@@ -15,22 +17,32 @@ use std::{
 pub struct Sftp {
     inner: ssh2::Sftp,
     inner_session: ssh2::Session,
-    stream: Arc<Async<TcpStream>>,
+    stream: Arc<Transport>,
+    /// Shared with the files, which use the same libssh2 SFTP state.
+    interrupted: Arc<Interrupted>,
 }
 
 /// See [`File`](ssh2::File).
 pub struct File {
     inner: ssh2::File,
+    path: PathBuf,
     inner_session: ssh2::Session,
-    stream: Arc<Async<TcpStream>>,
+    stream: Arc<Transport>,
+    read_timer: Option<Timer>,
+    write_timer: Option<Timer>,
+    interrupted: Arc<Interrupted>,
+    /// Where a seek started with `tokio::io::AsyncSeek` goes.
+    #[cfg(feature = "tokio")]
+    seek: Option<io::SeekFrom>,
 }
 
 impl Sftp {
-    pub(crate) fn new<'b>(sftp: ssh2::Sftp, session: ssh2::Session, stream: Arc<Async<TcpStream>>) -> Sftp {
+    pub(crate) fn new(sftp: ssh2::Sftp, session: ssh2::Session, stream: Arc<Transport>) -> Sftp {
         Sftp {
             inner: sftp,
             inner_session: session,
             stream,
+            interrupted: Arc::new(Interrupted::default()),
         }
     }
 
@@ -42,11 +54,18 @@ impl Sftp {
         mode: i32,
         open_type: ssh2::OpenType,
     ) -> Result<File, Error> {
-        let file = run_ssh2_fn(&self.stream, &self.inner_session,|| {
+        let op = Op::new("open").path(filename).on(&self.interrupted);
+        let file = run_ssh2_fn(&self.stream, &self.inner_session, op, || {
             self.inner.open_mode(filename, flags, mode, open_type)
         })
         .await?;
-        Ok(File::new(file, self.inner_session.clone(), self.stream.clone()))
+        Ok(File::new(
+            file,
+            filename,
+            self.inner_session.clone(),
+            self.stream.clone(),
+            self.interrupted.clone(),
+        ))
     }
 
     /// See [`open`](ssh2::Sftp::open).
@@ -85,9 +104,7 @@ impl Sftp {
 
                     ret.push((dirname.join(&filename), stat))
                 }
-                Err(Error::SSH2(ref e)) if e.code() == ssh2::ErrorCode::Session(-16) => {
-                    break;
-                }
+                Err(ref e) if e.kind() == ErrorKind::Eof => break,
                 Err(e) => {
                     return Err(e);
                 }
@@ -98,42 +115,50 @@ impl Sftp {
 
     /// See [`mkdir`](ssh2::Sftp::mkdir).
     pub async fn mkdir(&self, filename: &Path, mode: i32) -> Result<(), Error> {
-        run_ssh2_fn(&self.stream, &self.inner_session,|| self.inner.mkdir(filename, mode)).await
+        let op = Op::new("mkdir").path(filename).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || self.inner.mkdir(filename, mode)).await
     }
 
     /// See [`rmdir`](ssh2::Sftp::rmdir).
     pub async fn rmdir(&self, filename: &Path) -> Result<(), Error> {
-        run_ssh2_fn(&self.stream, &self.inner_session,|| self.inner.rmdir(filename)).await
+        let op = Op::new("rmdir").path(filename).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || self.inner.rmdir(filename)).await
     }
 
     /// See [`stat`](ssh2::Sftp::stat).
     pub async fn stat(&self, filename: &Path) -> Result<ssh2::FileStat, Error> {
-        run_ssh2_fn(&self.stream, &self.inner_session,|| self.inner.stat(filename)).await
+        let op = Op::new("stat").path(filename).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || self.inner.stat(filename)).await
     }
 
     /// See [`lstat`](ssh2::Sftp::lstat).
     pub async fn lstat(&self, filename: &Path) -> Result<ssh2::FileStat, Error> {
-        run_ssh2_fn(&self.stream, &self.inner_session,|| self.inner.lstat(filename)).await
+        let op = Op::new("lstat").path(filename).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || self.inner.lstat(filename)).await
     }
 
     /// See [`setstat`](ssh2::Sftp::setstat).
     pub async fn setstat(&self, filename: &Path, stat: ssh2::FileStat) -> Result<(), Error> {
-        run_ssh2_fn(&self.stream, &self.inner_session,|| self.inner.setstat(filename, stat.clone())).await
+        let op = Op::new("setstat").path(filename).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || self.inner.setstat(filename, stat.clone())).await
     }
 
     /// See [`symlink`](ssh2::Sftp::symlink).
     pub async fn symlink(&self, path: &Path, target: &Path) -> Result<(), Error> {
-        run_ssh2_fn(&self.stream, &self.inner_session,|| self.inner.symlink(path, target)).await
+        let op = Op::new("symlink").path(path).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || self.inner.symlink(path, target)).await
     }
 
     /// See [`readlink`](ssh2::Sftp::readlink).
     pub async fn readlink(&self, path: &Path) -> Result<PathBuf, Error> {
-        run_ssh2_fn(&self.stream, &self.inner_session,|| self.inner.readlink(path)).await
+        let op = Op::new("readlink").path(path).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || self.inner.readlink(path)).await
     }
 
     /// See [`realpath`](ssh2::Sftp::realpath).
     pub async fn realpath(&self, path: &Path) -> Result<PathBuf, Error> {
-        run_ssh2_fn(&self.stream, &self.inner_session,|| self.inner.realpath(path)).await
+        let op = Op::new("realpath").path(path).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || self.inner.realpath(path)).await
     }
 
     /// See [`rename`](ssh2::Sftp::rename).
@@ -143,12 +168,14 @@ impl Sftp {
         dst: &Path,
         flags: Option<ssh2::RenameFlags>,
     ) -> Result<(), Error> {
-        run_ssh2_fn(&self.stream, &self.inner_session,|| self.inner.rename(src, dst, flags)).await
+        let op = Op::new("rename").path(src).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || self.inner.rename(src, dst, flags)).await
     }
 
     /// See [`unlink`](ssh2::Sftp::unlink).
     pub async fn unlink(&self, file: &Path) -> Result<(), Error> {
-        run_ssh2_fn(&self.stream, &self.inner_session,|| self.inner.unlink(file)).await
+        let op = Op::new("unlink").path(file).on(&self.interrupted);
+        run_ssh2_fn(&self.stream, &self.inner_session, op, || self.inner.unlink(file)).await
     }
 
     /// See [`unlink`](ssh2::Sftp::shutdown).
@@ -156,34 +183,48 @@ impl Sftp {
     /// When called it unwraps the sftp handle and calls libssh2_sftp_shutdown, which will likely return EAGAIN,
     /// but when we try to call it a second time it fails because the handle is already unwrapped.
     pub async fn shutdown(mut self) -> Result<(), Error> {
-        run_ssh2_fn(&self.stream.clone(), &self.inner_session.clone(), || self.inner.shutdown()).await
+        let interrupted = self.interrupted.clone();
+        let op = Op::new("sftp shutdown").on(&interrupted);
+        run_ssh2_fn(&self.stream.clone(), &self.inner_session.clone(), op, || self.inner.shutdown()).await
     }
 }
 
 impl File {
-    pub(crate) fn new(file: ssh2::File, session: ssh2::Session, stream: Arc<Async<TcpStream>>) -> File {
+    pub(crate) fn new(
+        file: ssh2::File,
+        path: &Path,
+        session: ssh2::Session,
+        stream: Arc<Transport>,
+        interrupted: Arc<Interrupted>,
+    ) -> File {
         File {
             inner: file,
+            path: path.to_owned(),
             inner_session: session,
             stream,
+            read_timer: None,
+            write_timer: None,
+            interrupted,
+            #[cfg(feature = "tokio")]
+            seek: None,
         }
     }
 
     /// See [`setstat`](ssh2::File::setstat).
     pub async fn setstat(&mut self, stat: FileStat) -> Result<(), Error> {
         let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream,  &self.inner_session, || inner.setstat(stat.clone())).await
+        let op = Op::new("fsetstat").path(&self.path).on(&self.interrupted);
+        run_ssh2_fn(&self.stream,  &self.inner_session, op, || inner.setstat(stat.clone())).await
     }
 
     /// See [`stat`](ssh2::File::stat).
     pub async fn stat(&mut self) -> Result<FileStat, Error> {
         let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream,  &self.inner_session, || inner.stat()).await
+        let op = Op::new("fstat").path(&self.path).on(&self.interrupted);
+        run_ssh2_fn(&self.stream,  &self.inner_session, op, || inner.stat()).await
     }
 
-    #[allow(missing_docs)]
-    /// See [`statvfs`](ssh2::File::statvfs).
-    // TODO
+    // TODO: See [`statvfs`](ssh2::File::statvfs).
     /*
     pub async fn statvfs(&mut self) -> Result<raw::LIBSSH2_SFTP_STATVFS, Error> {
         run_ssh2_fn(&self.stream.clone(),  self.inner_session, || self.inner.statvfs().await
@@ -191,21 +232,37 @@ impl File {
     */
 
     /// See [`readdir`](ssh2::File::readdir).
+    ///
+    /// Once all entries have been read this fails with an error of kind
+    /// [`ErrorKind::Eof`].
     pub async fn readdir(&mut self) -> Result<(PathBuf, FileStat), Error> {
         let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream,  &self.inner_session, || inner.readdir()).await
+        let op = Op::new("readdir").path(&self.path).on(&self.interrupted);
+        run_ssh2_fn(&self.stream,  &self.inner_session, op, || {
+            inner.readdir().map_err(|e| match e.code() {
+                // ssh2 reports the end of the directory as a generic file
+                // error.
+                ErrorCode::Session(raw::LIBSSH2_ERROR_FILE) => {
+                    ssh2::Error::new(ErrorCode::SFTP(raw::LIBSSH2_FX_EOF), "no more files")
+                }
+                _ => e,
+            })
+        })
+        .await
     }
 
     /// See [`fsync`](ssh2::File::fsync).
     pub async fn fsync(&mut self) -> Result<(), Error> {
         let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream,  &self.inner_session, || inner.fsync()).await
+        let op = Op::new("fsync").path(&self.path).on(&self.interrupted);
+        run_ssh2_fn(&self.stream,  &self.inner_session, op, || inner.fsync()).await
     }
 
     /// See [`close`](ssh2::File::close).
     pub async fn close(mut self) -> Result<(), Error> {
         let inner = &mut self.inner;
-        run_ssh2_fn(&self.stream,  &self.inner_session, || inner.close()).await
+        let op = Op::new("close").path(&self.path).on(&self.interrupted);
+        run_ssh2_fn(&self.stream,  &self.inner_session, op, || inner.close()).await
     }
 }
 
@@ -217,7 +274,7 @@ impl AsyncRead for File {
     ) -> Poll<io::Result<usize>> {
         let this = self.get_mut();
         let inner = &mut this.inner;
-        poll_ssh2_io_op(cx, &this.stream.clone(), &this.inner_session, || inner.read(buf))
+        poll_ssh2_io_op(cx, &this.stream.clone(), &this.inner_session, &mut this.read_timer, || inner.read(buf))
     }
 }
 
@@ -229,13 +286,13 @@ impl AsyncWrite for File {
     ) -> Poll<Result<usize, io::Error>> {
         let this = self.get_mut();
         let inner = &mut this.inner;
-        poll_ssh2_io_op(cx, &this.stream, &this.inner_session, || inner.write(buf))
+        poll_ssh2_io_op(cx, &this.stream, &this.inner_session, &mut this.write_timer, || inner.write(buf))
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
         let this = self.get_mut();
         let inner = &mut this.inner;
-        poll_ssh2_io_op(cx, &this.stream, &this.inner_session, || inner.flush())
+        poll_ssh2_io_op(cx, &this.stream, &this.inner_session, &mut this.write_timer, || inner.flush())
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
@@ -244,6 +301,7 @@ impl AsyncWrite for File {
         poll_ssh2_io_op(cx, 
             &this.stream,
             &this.inner_session, 
+            &mut this.write_timer,
             || inner.close().map_err(|e| io::Error::from(ssh2::Error::from_errno(e.code())))
         )
     }
@@ -254,3 +312,75 @@ impl Seek for File {
         self.inner.seek(pos)
     }
 }
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for File {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        poll_read_buf(buf, |buf| AsyncRead::poll_read(self, cx, buf))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for File {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(self, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(self, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_close(self, cx)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncSeek for File {
+    fn start_seek(self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        self.get_mut().seek = Some(position);
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        let position = match this.seek {
+            // ssh2 looks the size up with a blocking stat and hides EAGAIN.
+            Some(io::SeekFrom::End(offset)) => {
+                let inner = &mut this.inner;
+                let stat = poll_ssh2_io_op(cx, &this.stream, &this.inner_session, &mut this.read_timer, || {
+                    inner.stat().map_err(io::Error::from)
+                });
+                let size = match futures::ready!(stat) {
+                    Ok(stat) => stat.size.unwrap_or(0),
+                    Err(e) => {
+                        this.seek = None;
+                        return Poll::Ready(Err(e));
+                    }
+                };
+                match size.checked_add_signed(offset) {
+                    Some(position) => io::SeekFrom::Start(position),
+                    None => {
+                        this.seek = None;
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "invalid seek to a negative or overflowing position",
+                        )));
+                    }
+                }
+            }
+            Some(position) => position,
+            None => io::SeekFrom::Current(0),
+        };
+        this.seek = None;
+        Poll::Ready(this.inner.seek(position))
+    }
+}
@@ -0,0 +1,229 @@
+use crate::{Error, ErrorKind};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::{
+    error, io,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+/// A CONNECT request read from a SOCKS client.
+#[derive(Debug)]
+pub(crate) struct Request {
+    /// The host to connect to, resolved by the server if it is a name.
+    pub host: String,
+    pub port: u16,
+    version: Version,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    /// SOCKS 4 or 4a, which differ in the request only.
+    V4,
+    V5,
+}
+
+const V4_GRANTED: u8 = 0x5a;
+const V4_REJECTED: u8 = 0x5b;
+
+const V5_SUCCEEDED: u8 = 0x00;
+const V5_GENERAL_FAILURE: u8 = 0x01;
+const V5_NOT_ALLOWED: u8 = 0x02;
+const V5_HOST_UNREACHABLE: u8 = 0x04;
+const V5_CONNECTION_REFUSED: u8 = 0x05;
+const V5_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const V5_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+const CONNECT: u8 = 0x01;
+const NO_AUTHENTICATION: u8 = 0x00;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+impl Request {
+    /// Reads a request, going through the SOCKS 5 method negotiation on the
+    /// way. Requests this server cannot handle are refused before failing.
+    pub(crate) async fn read<S>(stream: &mut S) -> io::Result<Request>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match read_u8(stream).await? {
+            4 => read_v4(stream).await,
+            5 => read_v5(stream).await,
+            version => Err(invalid(format!("unknown SOCKS version {}", version))),
+        }
+    }
+
+    /// Tells the client whether the connection was made, `error` being why
+    /// it was not.
+    pub(crate) async fn reply<S>(&self, stream: &mut S, error: Option<&Error>) -> io::Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let reply = match self.version {
+            Version::V4 => {
+                let code = if error.is_none() {
+                    V4_GRANTED
+                } else {
+                    V4_REJECTED
+                };
+                v4_reply(code)
+            }
+            Version::V5 => {
+                let code = error.map_or(V5_SUCCEEDED, v5_error_code);
+                v5_reply(code)
+            }
+        };
+        stream.write_all(&reply).await?;
+        stream.flush().await
+    }
+}
+
+/// Reads the rest of a SOCKS 4 or 4a request.
+async fn read_v4<S>(stream: &mut S) -> io::Result<Request>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0; 7];
+    stream.read_exact(&mut header).await?;
+    let command = header[0];
+    let port = u16::from_be_bytes([header[1], header[2]]);
+    let ip = Ipv4Addr::new(header[3], header[4], header[5], header[6]);
+    // The user id is of no use as the server authenticated us already.
+    read_nul_terminated(stream).await?;
+    // SOCKS 4a marks a host name following the user id with 0.0.0.x.
+    let host = match ip.octets() {
+        [0, 0, 0, x] if x != 0 => read_nul_terminated(stream).await?,
+        _ => ip.to_string(),
+    };
+    if command != CONNECT {
+        stream.write_all(&v4_reply(V4_REJECTED)).await.ok();
+        return Err(invalid(format!("unsupported SOCKS command {}", command)));
+    }
+    Ok(Request {
+        host,
+        port,
+        version: Version::V4,
+    })
+}
+
+/// Negotiates the authentication method and reads a SOCKS 5 request.
+async fn read_v5<S>(stream: &mut S) -> io::Result<Request>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut methods = vec![0; read_u8(stream).await? as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTHENTICATION) {
+        stream.write_all(&[5, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(invalid("the SOCKS client requires authentication"));
+    }
+    stream.write_all(&[5, NO_AUTHENTICATION]).await?;
+    stream.flush().await?;
+
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, command, _, address_type] = header;
+    if version != 5 {
+        return Err(invalid(format!("unknown SOCKS version {}", version)));
+    }
+    let host = match address_type {
+        1 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut name = vec![0; read_u8(stream).await? as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name).map_err(|_| invalid("invalid host name"))?
+        }
+        4 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => {
+            stream
+                .write_all(&v5_reply(V5_ADDRESS_TYPE_NOT_SUPPORTED))
+                .await
+                .ok();
+            return Err(invalid(format!(
+                "unknown SOCKS address type {}",
+                address_type
+            )));
+        }
+    };
+    let mut port = [0; 2];
+    stream.read_exact(&mut port).await?;
+    if command != CONNECT {
+        stream
+            .write_all(&v5_reply(V5_COMMAND_NOT_SUPPORTED))
+            .await
+            .ok();
+        return Err(invalid(format!("unsupported SOCKS command {}", command)));
+    }
+    Ok(Request {
+        host,
+        port: u16::from_be_bytes(port),
+        version: Version::V5,
+    })
+}
+
+/// A SOCKS 4 reply with `code`.
+fn v4_reply(code: u8) -> Vec<u8> {
+    vec![0, code, 0, 0, 0, 0, 0, 0]
+}
+
+/// A SOCKS 5 reply with `code`. The bound address is left empty, which
+/// clients of CONNECT requests ignore.
+fn v5_reply(code: u8) -> Vec<u8> {
+    vec![5, code, 0, 1, 0, 0, 0, 0, 0, 0]
+}
+
+/// The SOCKS 5 reply code that tells the client best why the server could
+/// not open a channel.
+fn v5_error_code(error: &Error) -> u8 {
+    match error.kind() {
+        ErrorKind::ChannelOpenFailed => {
+            // libssh2 only tells the reason in the message, which is that
+            // of the innermost error.
+            let mut source: &dyn error::Error = error;
+            while let Some(e) = source.source() {
+                source = e;
+            }
+            let message = source.to_string();
+            if message.contains("administratively prohibited") {
+                V5_NOT_ALLOWED
+            } else if message.contains("connect failed") {
+                V5_CONNECTION_REFUSED
+            } else {
+                V5_GENERAL_FAILURE
+            }
+        }
+        ErrorKind::Timeout => V5_HOST_UNREACHABLE,
+        _ => V5_GENERAL_FAILURE,
+    }
+}
+
+async fn read_u8<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<u8> {
+    let mut byte = [0];
+    stream.read_exact(&mut byte).await?;
+    Ok(byte[0])
+}
+
+/// Reads a string terminated by a NUL byte, as SOCKS 4 uses.
+async fn read_nul_terminated<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    loop {
+        match read_u8(stream).await? {
+            0 => break,
+            _ if bytes.len() == 255 => return Err(invalid("overlong string in SOCKS 4 request")),
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid("invalid string in SOCKS 4 request"))
+}
+
+fn invalid<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
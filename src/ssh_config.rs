@@ -0,0 +1,678 @@
+use crate::{
+    connect::ConnectOptions,
+    host_key::{InsecureAcceptAll, KnownHostsVerifier},
+    util::{home_dir, local_user, unblock, wildcard_match},
+    Error,
+};
+use ssh2::MethodType;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+/// How deeply `Include` directives may nest, as in OpenSSH.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Identity files tried when the configuration does not name any.
+const DEFAULT_IDENTITY_FILES: &[&str] = &["~/.ssh/id_rsa", "~/.ssh/id_ecdsa", "~/.ssh/id_ed25519"];
+
+/// The `StrictHostKeyChecking` setting of a host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StrictHostKeyChecking {
+    /// `yes`: only known hosts are accepted.
+    Yes,
+    /// `accept-new`: new hosts are added, changed keys are rejected.
+    AcceptNew,
+    /// `no` or `off`: every key is accepted.
+    No,
+    /// `ask`, the default. There is no one to ask, so this behaves like
+    /// [`Yes`](StrictHostKeyChecking::Yes).
+    Ask,
+}
+
+impl StrictHostKeyChecking {
+    fn parse(value: &str) -> Option<StrictHostKeyChecking> {
+        match value.to_lowercase().as_str() {
+            "yes" | "true" => Some(StrictHostKeyChecking::Yes),
+            "accept-new" => Some(StrictHostKeyChecking::AcceptNew),
+            "no" | "off" | "false" => Some(StrictHostKeyChecking::No),
+            "ask" => Some(StrictHostKeyChecking::Ask),
+            _ => None,
+        }
+    }
+}
+
+/// A single criterion of a `Match` line.
+#[derive(Clone, Debug)]
+struct Criterion {
+    negated: bool,
+    name: String,
+    arg: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+enum Directive {
+    Host(Vec<String>),
+    Match(Vec<Criterion>),
+    Option(String, Vec<String>),
+    /// The directives of every file an `Include` line expanded to.
+    Include(Vec<Directive>),
+}
+
+/// A parsed OpenSSH client configuration file, such as `~/.ssh/config`.
+///
+/// `Host` and `Match` blocks, `Include` and the usual first-obtained-value
+/// wins semantics are supported. [`resolve`](SshConfig::resolve) computes the
+/// settings for a host, which [`Session::connect_with_config`] turns into a
+/// connection.
+///
+/// `Match` criteria that need outside information (`exec`, `localnetwork`,
+/// `tagged`) and `canonical` never match. Keywords this crate has no use for
+/// are kept and can be looked up with [`HostConfig::get`].
+///
+/// [`Session::connect_with_config`]: crate::Session::connect_with_config
+///
+/// ```rust,no_run
+/// use async_ssh2::{Session, SshConfig};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let config = SshConfig::read(SshConfig::user_file()).await.unwrap();
+///     let sess = Session::connect_with_config(&config, "prod-db").await.unwrap();
+///     sess.userauth_with_config(&config.resolve("prod-db")).await.unwrap();
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SshConfig {
+    directives: Vec<Directive>,
+}
+
+impl SshConfig {
+    /// Reads the configuration at `path`, following `Include` directives.
+    /// Relative includes are looked up next to `path`. A missing file is
+    /// treated as empty.
+    pub async fn read<P: AsRef<Path>>(path: P) -> Result<SshConfig, Error> {
+        let path = path.as_ref().to_owned();
+        let directives = unblock(move || {
+            let base = path.parent().unwrap_or_else(|| Path::new("")).to_owned();
+            match fs::read_to_string(&path) {
+                Ok(contents) => parse(&contents, &path, &base, 0),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+                Err(e) => Err(e),
+            }
+        })
+        .await?;
+        Ok(SshConfig { directives })
+    }
+
+    /// The user's `~/.ssh/config`.
+    pub fn user_file() -> PathBuf {
+        home_dir().join(".ssh").join("config")
+    }
+
+    /// Computes the settings for `host`, the name given on the command line
+    /// in `ssh host`.
+    pub fn resolve(&self, host: &str) -> HostConfig {
+        let mut resolver = Resolver {
+            config: HostConfig {
+                host: host.to_owned(),
+                options: HashMap::new(),
+                identity_files: Vec::new(),
+            },
+            final_pass: false,
+            wants_final: false,
+        };
+        resolver.walk(&self.directives, true);
+        // Like OpenSSH, evaluate the file once more if `Match final` was seen.
+        if resolver.wants_final {
+            resolver.final_pass = true;
+            resolver.walk(&self.directives, true);
+        }
+        resolver.config
+    }
+
+    /// Resolves a `ProxyJump` hop of the form `[user@]host[:port]` or
+    /// `ssh://[user@]host[:port]`. A user or port given in the hop takes
+    /// precedence over the configuration.
+    pub(crate) fn resolve_jump(&self, hop: &str) -> Result<HostConfig, Error> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid ProxyJump hop `{}`", hop),
+            )
+        };
+        let spec = hop.strip_prefix("ssh://").unwrap_or(hop);
+        let (user, spec) = match spec.rfind('@') {
+            Some(i) => (Some(&spec[..i]), &spec[i + 1..]),
+            None => (None, spec),
+        };
+        let (host, port) = if let Some(rest) = spec.strip_prefix('[') {
+            let end = rest.find(']').ok_or_else(invalid)?;
+            match &rest[end + 1..] {
+                "" => (&rest[..end], None),
+                port => (
+                    &rest[..end],
+                    Some(port.strip_prefix(':').ok_or_else(invalid)?),
+                ),
+            }
+        } else {
+            match spec.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (spec, None),
+            }
+        };
+        if host.is_empty() {
+            return Err(invalid().into());
+        }
+        let mut config = self.resolve(host);
+        if let Some(user) = user {
+            config
+                .options
+                .insert("user".to_owned(), vec![user.to_owned()]);
+        }
+        if let Some(port) = port {
+            port.parse::<u16>().map_err(|_| invalid())?;
+            config
+                .options
+                .insert("port".to_owned(), vec![port.to_owned()]);
+        }
+        Ok(config)
+    }
+}
+
+fn parse(contents: &str, path: &Path, base: &Path, depth: usize) -> io::Result<Vec<Directive>> {
+    let mut directives = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let error = |msg: String| {
+            let msg = format!("{}:{}: {}", path.display(), i + 1, msg);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let end = line
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(line.len());
+        let keyword = line[..end].to_lowercase();
+        let rest = line[end..].trim_start();
+        let rest = rest.strip_prefix('=').unwrap_or(rest).trim_start();
+
+        // The command is handed to the shell as is.
+        if keyword == "proxycommand" {
+            if rest.is_empty() {
+                return Err(error("missing argument for ProxyCommand".to_owned()));
+            }
+            directives.push(Directive::Option(keyword, vec![rest.to_owned()]));
+            continue;
+        }
+        let args = split_args(rest).ok_or_else(|| error("unterminated quote".to_owned()))?;
+        if args.is_empty() {
+            return Err(error(format!("missing argument for {}", &line[..end])));
+        }
+        let directive = match keyword.as_str() {
+            "host" => Directive::Host(args),
+            "match" => Directive::Match(parse_match(&args).map_err(error)?),
+            "include" => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(error("too many nested Include directives".to_owned()));
+                }
+                let mut included = Vec::new();
+                for arg in &args {
+                    let pattern = base.join(expand_tilde(arg));
+                    for file in glob(&pattern)? {
+                        let contents = fs::read_to_string(&file)?;
+                        included.extend(parse(&contents, &file, base, depth + 1)?);
+                    }
+                }
+                Directive::Include(included)
+            }
+            _ => {
+                validate(&keyword, &args[0]).map_err(error)?;
+                Directive::Option(keyword, args)
+            }
+        };
+        directives.push(directive);
+    }
+    Ok(directives)
+}
+
+/// Rejects malformed values of the keywords [`HostConfig`] interprets.
+fn validate(keyword: &str, value: &str) -> Result<(), String> {
+    let valid = match keyword {
+        "port" => value.parse::<u16>().is_ok_and(|port| port != 0),
+        "serveraliveinterval" | "serveralivecountmax" | "connecttimeout" => {
+            value.parse::<u32>().is_ok() || (keyword == "connecttimeout" && value == "none")
+        }
+        "stricthostkeychecking" => StrictHostKeyChecking::parse(value).is_some(),
+        _ => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("invalid value `{}` for {}", value, keyword))
+    }
+}
+
+/// Splits the arguments of a line at whitespace, honouring quotes and
+/// stopping at a `#` comment. Returns `None` for an unterminated quote.
+fn split_args(s: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if matches!(chars.peek(), None | Some('#')) {
+            return Some(args);
+        }
+        let mut arg = String::new();
+        let mut quote = None;
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (_, '\\') if matches!(chars.peek(), Some('\\' | '"' | '\'' | ' ')) => {
+                    arg.push(chars.next().unwrap())
+                }
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), c) => arg.push(c),
+                (None, '"' | '\'') => quote = Some(c),
+                (None, c) if c.is_whitespace() => break,
+                (None, c) => arg.push(c),
+            }
+        }
+        if quote.is_some() {
+            return None;
+        }
+        args.push(arg);
+    }
+}
+
+fn parse_match(args: &[String]) -> Result<Vec<Criterion>, String> {
+    let mut criteria = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (negated, name) = match arg.strip_prefix('!') {
+            Some(name) => (true, name.to_lowercase()),
+            None => (false, arg.to_lowercase()),
+        };
+        let arg = match name.as_str() {
+            "all" | "canonical" | "final" => None,
+            "host" | "originalhost" | "user" | "localuser" | "exec" | "localnetwork" | "tagged" => {
+                match args.next() {
+                    Some(arg) => Some(arg.clone()),
+                    None => return Err(format!("missing argument for Match {}", name)),
+                }
+            }
+            _ => return Err(format!("unsupported Match criterion `{}`", name)),
+        };
+        criteria.push(Criterion { negated, name, arg });
+    }
+    Ok(criteria)
+}
+
+fn expand_tilde(path: &str) -> PathBuf {
+    match path.strip_prefix('~') {
+        Some("") => home_dir(),
+        Some(rest) if rest.starts_with('/') => home_dir().join(&rest[1..]),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Expands `*` and `?` wildcards in any component of `pattern`. Only
+/// existing files are returned, sorted like `glob(3)` does.
+fn glob(pattern: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![PathBuf::new()];
+    for component in pattern.components() {
+        let name = match component {
+            Component::Normal(name) => name.to_string_lossy(),
+            other => {
+                paths.iter_mut().for_each(|path| path.push(other));
+                continue;
+            }
+        };
+        if !name.contains(['*', '?']) {
+            paths.iter_mut().for_each(|path| path.push(&*name));
+            continue;
+        }
+        let mut expanded = Vec::new();
+        for dir in &paths {
+            let entries = match fs::read_dir(if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            }) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries {
+                let file_name = entry?.file_name();
+                let file_name = file_name.to_string_lossy();
+                // Like the shell, wildcards don't match hidden files.
+                if !file_name.starts_with('.') && wildcard_match(&name, &file_name) {
+                    expanded.push(dir.join(&*file_name));
+                }
+            }
+        }
+        expanded.sort();
+        paths = expanded;
+    }
+    Ok(paths.into_iter().filter(|path| path.is_file()).collect())
+}
+
+/// Whether `s` matches a list of patterns, some of which may be negated
+/// with `!`. A negated match overrides any other.
+fn match_patterns<'a, I>(patterns: I, s: &str, ignore_case: bool) -> bool
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let s = if ignore_case {
+        s.to_lowercase()
+    } else {
+        s.to_owned()
+    };
+    let mut matched = false;
+    for pattern in patterns {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let pattern = if ignore_case {
+            pattern.to_lowercase()
+        } else {
+            pattern.to_owned()
+        };
+        if wildcard_match(&pattern, &s) {
+            if negated {
+                return false;
+            }
+            matched = true;
+        }
+    }
+    matched
+}
+
+struct Resolver {
+    config: HostConfig,
+    final_pass: bool,
+    wants_final: bool,
+}
+
+impl Resolver {
+    fn walk(&mut self, directives: &[Directive], mut active: bool) {
+        for directive in directives {
+            match directive {
+                Directive::Host(patterns) => {
+                    let host = &self.config.host;
+                    active = match_patterns(patterns.iter().map(String::as_str), host, true);
+                }
+                Directive::Match(criteria) => {
+                    active = criteria.iter().all(|c| self.eval(c) != c.negated);
+                }
+                Directive::Option(keyword, args) if active => self.set(keyword, args),
+                Directive::Option(..) => {}
+                // Blocks inside an included file don't leak out of it, and
+                // nothing in it applies if the `Include` line itself didn't.
+                Directive::Include(included) if active => self.walk(included, true),
+                Directive::Include(_) => {}
+            }
+        }
+    }
+
+    fn eval(&mut self, criterion: &Criterion) -> bool {
+        let list = || criterion.arg.as_deref().unwrap_or("").split(',');
+        match criterion.name.as_str() {
+            "all" => true,
+            "final" => {
+                self.wants_final = true;
+                self.final_pass
+            }
+            "host" => match_patterns(list(), &self.config.host_name(), true),
+            "originalhost" => match_patterns(list(), &self.config.host, true),
+            "user" => match_patterns(list(), &self.config.remote_user(), false),
+            "localuser" => match_patterns(list(), &local_user(), false),
+            _ => false,
+        }
+    }
+
+    fn set(&mut self, keyword: &str, args: &[String]) {
+        let options = &mut self.config.options;
+        match keyword {
+            // Like OpenSSH, ignore duplicates, which also keeps the final
+            // pass from adding every identity twice.
+            "identityfile" => {
+                if !self.config.identity_files.contains(&args[0]) {
+                    self.config.identity_files.push(args[0].clone());
+                }
+            }
+            // Whichever of the two is seen first wins.
+            "proxyjump" | "proxycommand" => {
+                if !options.contains_key("proxyjump") && !options.contains_key("proxycommand") {
+                    options.insert(keyword.to_owned(), args.to_vec());
+                }
+            }
+            _ => {
+                options
+                    .entry(keyword.to_owned())
+                    .or_insert_with(|| args.to_vec());
+            }
+        }
+    }
+}
+
+/// The settings [`SshConfig::resolve`] computed for a host.
+#[derive(Clone, Debug)]
+pub struct HostConfig {
+    host: String,
+    options: HashMap<String, Vec<String>>,
+    identity_files: Vec<String>,
+}
+
+impl HostConfig {
+    /// The name the configuration was resolved for.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The arguments of the first value obtained for `keyword`, which is
+    /// case insensitive. `IdentityFile` is collected by
+    /// [`identity_files`](HostConfig::identity_files) instead.
+    pub fn get(&self, keyword: &str) -> Option<&[String]> {
+        self.options.get(&keyword.to_lowercase()).map(Vec::as_slice)
+    }
+
+    fn first(&self, keyword: &str) -> Option<&str> {
+        self.options.get(keyword).map(|args| args[0].as_str())
+    }
+
+    /// `HostName`, or [`host`](HostConfig::host) if it isn't set.
+    pub fn host_name(&self) -> String {
+        match self.first("hostname") {
+            Some(name) => self.expand_tokens(name, &self.host),
+            None => self.host.clone(),
+        }
+    }
+
+    /// `Port`, 22 by default.
+    pub fn port(&self) -> u16 {
+        self.first("port")
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(22)
+    }
+
+    /// `User`, if set.
+    pub fn user(&self) -> Option<&str> {
+        self.first("user")
+    }
+
+    /// The user to log in as: `User` or the local user name.
+    fn remote_user(&self) -> String {
+        self.user().map(str::to_owned).unwrap_or_else(local_user)
+    }
+
+    /// Every `IdentityFile` that applies, in order, or OpenSSH's default
+    /// identities if there is none.
+    pub fn identity_files(&self) -> Vec<PathBuf> {
+        let files = if self.identity_files.is_empty() {
+            DEFAULT_IDENTITY_FILES
+                .iter()
+                .map(|f| f.to_string())
+                .collect()
+        } else {
+            self.identity_files.clone()
+        };
+        files
+            .iter()
+            .filter(|file| !file.eq_ignore_ascii_case("none"))
+            .map(|file| self.expand_path(file))
+            .collect()
+    }
+
+    /// `ProxyJump` as written, a comma separated list of hops.
+    pub fn proxy_jump(&self) -> Option<&str> {
+        let args = self.options.get("proxyjump")?;
+        Some(args[0].as_str()).filter(|jump| !jump.eq_ignore_ascii_case("none"))
+    }
+
+    /// `ProxyCommand` with its `%h`, `%p`, `%r`, `%n` and `%%` tokens
+    /// expanded.
+    pub fn proxy_command(&self) -> Option<String> {
+        let command = self.first("proxycommand")?;
+        if command.eq_ignore_ascii_case("none") {
+            return None;
+        }
+        Some(self.expand_tokens(command, &self.host_name()))
+    }
+
+    /// `StrictHostKeyChecking`, [`Ask`](StrictHostKeyChecking::Ask) by
+    /// default.
+    pub fn strict_host_key_checking(&self) -> StrictHostKeyChecking {
+        self.first("stricthostkeychecking")
+            .and_then(StrictHostKeyChecking::parse)
+            .unwrap_or(StrictHostKeyChecking::Ask)
+    }
+
+    /// The first `UserKnownHostsFile`, `~/.ssh/known_hosts` by default.
+    pub fn user_known_hosts_file(&self) -> PathBuf {
+        match self.first("userknownhostsfile") {
+            Some(file) => self.expand_path(file),
+            None => KnownHostsVerifier::user_file(),
+        }
+    }
+
+    /// `Ciphers`, in `ssh_config` syntax.
+    pub fn ciphers(&self) -> Option<&str> {
+        self.first("ciphers")
+    }
+
+    /// `KexAlgorithms`, in `ssh_config` syntax.
+    pub fn kex_algorithms(&self) -> Option<&str> {
+        self.first("kexalgorithms")
+    }
+
+    /// `HostKeyAlgorithms`, in `ssh_config` syntax.
+    pub fn host_key_algorithms(&self) -> Option<&str> {
+        self.first("hostkeyalgorithms")
+    }
+
+    /// `MACs`, in `ssh_config` syntax.
+    pub fn macs(&self) -> Option<&str> {
+        self.first("macs")
+    }
+
+    /// `ServerAliveInterval`, `None` if keepalives are disabled.
+    pub fn server_alive_interval(&self) -> Option<Duration> {
+        let secs = self.first("serveraliveinterval")?.parse().ok()?;
+        Some(Duration::from_secs(secs)).filter(|interval| !interval.is_zero())
+    }
+
+    /// `ServerAliveCountMax`, 3 by default. Pass it to
+    /// [`Session::keepalive_task`](crate::Session::keepalive_task) along with
+    /// the interval to detect unresponsive servers.
+    pub fn server_alive_count_max(&self) -> u32 {
+        self.first("serveralivecountmax")
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(3)
+    }
+
+    /// `ConnectTimeout`, if set.
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        let secs = self.first("connecttimeout")?.parse().ok()?;
+        Some(Duration::from_secs(secs)).filter(|timeout| !timeout.is_zero())
+    }
+
+    /// [`ConnectOptions`] carrying the connect timeout, host key checking,
+    /// algorithm preferences and keepalive interval of this host.
+    ///
+    /// `StrictHostKeyChecking no` accepts every key, unlike OpenSSH which
+    /// still records new ones.
+    pub fn connect_options(&self) -> ConnectOptions {
+        let known_hosts = self.user_known_hosts_file();
+        let mut options = match self.strict_host_key_checking() {
+            StrictHostKeyChecking::Yes | StrictHostKeyChecking::Ask => {
+                ConnectOptions::new().host_key_verifier(KnownHostsVerifier::strict(known_hosts))
+            }
+            StrictHostKeyChecking::AcceptNew => {
+                ConnectOptions::new().host_key_verifier(KnownHostsVerifier::accept_new(known_hosts))
+            }
+            StrictHostKeyChecking::No => ConnectOptions::new().host_key_verifier(InsecureAcceptAll),
+        };
+        if let Some(timeout) = self.connect_timeout() {
+            options = options.connect_timeout(timeout);
+        }
+        let prefs = [
+            (
+                self.ciphers(),
+                &[MethodType::CryptCs, MethodType::CryptSc][..],
+            ),
+            (self.macs(), &[MethodType::MacCs, MethodType::MacSc]),
+            (self.kex_algorithms(), &[MethodType::Kex]),
+            (self.host_key_algorithms(), &[MethodType::HostKey]),
+        ];
+        for (prefs, method_types) in prefs.iter() {
+            if let Some(prefs) = prefs {
+                for method_type in method_types.iter() {
+                    options = options.method_pref(*method_type, prefs);
+                }
+            }
+        }
+        if let Some(interval) = self.server_alive_interval() {
+            options = options.keepalive(true, interval.as_secs().min(u32::MAX as u64) as u32);
+        }
+        options
+    }
+
+    fn expand_path(&self, path: &str) -> PathBuf {
+        let path = self.expand_tokens(path, &self.host_name());
+        expand_tilde(&path)
+    }
+
+    /// Expands the `%%`, `%h`, `%p`, `%r`, `%n`, `%u` and `%d` tokens, with
+    /// `%h` standing for `host_name`.
+    fn expand_tokens(&self, s: &str, host_name: &str) -> String {
+        let mut out = String::with_capacity(s.len());
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => out.push('%'),
+                Some('h') => out.push_str(host_name),
+                Some('p') => out.push_str(&self.port().to_string()),
+                Some('r') => out.push_str(&self.remote_user()),
+                Some('n') => out.push_str(&self.host),
+                Some('u') => out.push_str(&local_user()),
+                Some('d') => out.push_str(&home_dir().to_string_lossy()),
+                Some(c) => {
+                    out.push('%');
+                    out.push(c);
+                }
+                None => out.push('%'),
+            }
+        }
+        out
+    }
+}
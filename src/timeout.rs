@@ -0,0 +1,81 @@
+use futures::prelude::*;
+use std::{
+    cell::Cell,
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+thread_local! {
+    /// The override of the innermost `WithTimeout` being polled on this thread.
+    static OVERRIDE: Cell<Option<Option<Duration>>> = const { Cell::new(None) };
+}
+
+/// Runs `future` with a different operation timeout than the session's
+/// default (see [`Session::set_operation_timeout`](crate::Session::set_operation_timeout)).
+///
+/// Every operation `future` performs on any session is limited to `timeout`,
+/// or waits forever if it is `None`. This works with any executor: the
+/// override is in effect while `future` is being polled.
+///
+/// ```rust,no_run
+/// use async_ssh2::{with_timeout, Session};
+/// use std::{path::Path, time::Duration};
+///
+/// async fn stat(sess: &Session) -> Result<(), async_ssh2::Error> {
+///     let sftp = sess.sftp().await?;
+///     with_timeout(Some(Duration::from_secs(5)), sftp.stat(Path::new("/etc/motd"))).await?;
+///     Ok(())
+/// }
+/// ```
+pub fn with_timeout<F: Future>(timeout: Option<Duration>, future: F) -> WithTimeout<F> {
+    WithTimeout {
+        future: Box::pin(future),
+        timeout,
+    }
+}
+
+/// Future returned by [`with_timeout`].
+pub struct WithTimeout<F> {
+    future: Pin<Box<F>>,
+    timeout: Option<Duration>,
+}
+
+impl<F> fmt::Debug for WithTimeout<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WithTimeout")
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl<F: Future> Future for WithTimeout<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        struct Restore(Option<Option<Duration>>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                OVERRIDE.with(|o| o.set(self.0));
+            }
+        }
+
+        let _restore = Restore(OVERRIDE.with(|o| o.replace(Some(self.timeout))));
+        self.future.as_mut().poll(cx)
+    }
+}
+
+/// The timeout of an operation started now, given the session's default.
+pub(crate) fn effective(default: Option<Duration>) -> Option<Duration> {
+    OVERRIDE.with(Cell::get).unwrap_or(default)
+}
+
+/// The error an operation that ran out of time fails with.
+pub(crate) fn timeout_error(timeout: Duration) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("operation timed out after {:?}", timeout),
+    )
+}
@@ -0,0 +1,985 @@
+use crate::{timeout, util::Interrupted, Error};
+use async_io::{Async, Timer};
+use futures::{
+    channel::oneshot,
+    future::{self, poll_fn},
+    io::{AsyncRead, AsyncWrite},
+    ready,
+    task::{self, ArcWake},
+};
+use ssh2::{self, BlockDirections};
+#[cfg(unix)]
+use std::{
+    cell::Cell,
+    ffi::c_void,
+    os::unix::{
+        io::{AsRawFd, OwnedFd, RawFd},
+        net::UnixStream,
+    },
+    ptr,
+};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
+#[cfg(not(unix))]
+use std::net::TcpListener;
+use std::{
+    io::{self, Read},
+    mem,
+    net::{Shutdown, TcpStream},
+    pin::Pin,
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, TryLockError,
+    },
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Size of the buffers used to shuttle bytes between a bridged stream and
+/// the socket libssh2 talks to.
+const BRIDGE_BUF_SIZE: usize = 32 * 1024;
+
+/// How much of a proxy command's stderr is kept around, counted from the end.
+const STDERR_LIMIT: usize = 16 * 1024;
+
+/// How far ahead [`Socket::pending`] looks where it has to peek.
+#[cfg(not(unix))]
+const PEEK_LIMIT: usize = 64 * 1024;
+
+/// Byte stream a bridged [`Transport`] forwards libssh2's traffic over.
+trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for S {}
+
+/// A non-blocking socket registered with the `async-io` reactor.
+enum Socket {
+    Tcp(Async<TcpStream>),
+    #[cfg(unix)]
+    Unix(Async<UnixStream>),
+}
+
+impl Socket {
+    fn poll_readable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self {
+            Socket::Tcp(s) => s.poll_readable(cx),
+            #[cfg(unix)]
+            Socket::Unix(s) => s.poll_readable(cx),
+        }
+    }
+
+    fn poll_writable(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self {
+            Socket::Tcp(s) => s.poll_writable(cx),
+            #[cfg(unix)]
+            Socket::Unix(s) => s.poll_writable(cx),
+        }
+    }
+
+    fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        match self {
+            Socket::Tcp(s) => Pin::new(&mut &*s).poll_read(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(s) => Pin::new(&mut &*s).poll_read(cx, buf),
+        }
+    }
+
+    fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self {
+            Socket::Tcp(s) => Pin::new(&mut &*s).poll_write(cx, buf),
+            #[cfg(unix)]
+            Socket::Unix(s) => Pin::new(&mut &*s).poll_write(cx, buf),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Socket::Tcp(s) => s.get_ref().shutdown(how),
+            #[cfg(unix)]
+            Socket::Unix(s) => s.get_ref().shutdown(how),
+        }
+    }
+
+    /// How many received bytes are waiting to be read.
+    #[cfg(unix)]
+    fn pending(&self) -> io::Result<usize> {
+        let mut pending: libc::c_int = 0;
+        if unsafe { libc::ioctl(self.as_raw_fd(), libc::FIONREAD, &mut pending) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(pending as usize)
+    }
+
+    /// How many received bytes are waiting to be read, up to
+    /// [`PEEK_LIMIT`].
+    #[cfg(not(unix))]
+    fn pending(&self) -> io::Result<usize> {
+        let mut buf = vec![0; PEEK_LIMIT];
+        let res = match self {
+            Socket::Tcp(s) => s.get_ref().peek(&mut buf),
+        };
+        match res {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            res => res,
+        }
+    }
+
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Socket::Tcp(s) => s.as_raw_fd(),
+            Socket::Unix(s) => s.as_raw_fd(),
+        }
+    }
+
+    #[cfg(windows)]
+    fn as_raw_socket(&self) -> RawSocket {
+        match self {
+            Socket::Tcp(s) => s.as_raw_socket(),
+        }
+    }
+}
+
+/// Creates a connected pair of sockets: one for libssh2, one for the bridge.
+#[cfg(unix)]
+fn socket_pair() -> io::Result<(Socket, Socket)> {
+    let (a, b) = UnixStream::pair()?;
+    Ok((Socket::Unix(Async::new(a)?), Socket::Unix(Async::new(b)?)))
+}
+
+/// Creates a connected pair of sockets: one for libssh2, one for the bridge.
+#[cfg(not(unix))]
+fn socket_pair() -> io::Result<(Socket, Socket)> {
+    let (a, b) = tcp_pair()?;
+    Ok((Socket::Tcp(Async::new(a)?), Socket::Tcp(Async::new(b)?)))
+}
+
+#[cfg(not(unix))]
+fn tcp_pair() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let a = TcpStream::connect(listener.local_addr()?)?;
+    // Make sure nobody else raced us to the loopback port.
+    let b = loop {
+        let (b, peer) = listener.accept()?;
+        if peer == a.local_addr()? {
+            break b;
+        }
+    };
+    Ok((a, b))
+}
+
+/// A spawned `ProxyCommand` whose stdin and stdout carry the SSH traffic.
+///
+/// The child is killed and reaped when the transport is dropped.
+struct ProxyChild {
+    child: Child,
+    stderr: Arc<Mutex<Vec<u8>>>,
+    /// Resolves once the child's stderr has been read to the end.
+    stderr_closed: Mutex<Option<oneshot::Receiver<()>>>,
+}
+
+impl ProxyChild {
+    /// Spawns `command` and returns it along with the socket libssh2 should
+    /// use to talk to it.
+    fn spawn(mut command: Command) -> io::Result<(ProxyChild, Socket)> {
+        command.stderr(Stdio::piped());
+        #[cfg(unix)]
+        let (mut child, socket) = {
+            // Like OpenSSH, hand the child one end of a socket pair as both
+            // stdin and stdout so libssh2 can use the other end directly.
+            let (ours, theirs) = UnixStream::pair()?;
+            let stdin = OwnedFd::from(theirs.try_clone()?);
+            command.stdin(stdin).stdout(OwnedFd::from(theirs));
+            let child = command.spawn()?;
+            // `command` still owns the child's ends; close them so we notice
+            // when the child exits.
+            drop(command);
+            (child, Socket::Unix(Async::new(ours)?))
+        };
+        #[cfg(not(unix))]
+        let (mut child, socket) = {
+            command.stdin(Stdio::piped()).stdout(Stdio::piped());
+            let mut child = command.spawn()?;
+            let (ours, theirs) = tcp_pair()?;
+            let mut stdin = child.stdin.take().unwrap();
+            let mut stdout = child.stdout.take().unwrap();
+            let mut to_child = theirs.try_clone()?;
+            let mut from_child = theirs;
+            thread::spawn(move || {
+                let _ = io::copy(&mut to_child, &mut stdin);
+            });
+            thread::spawn(move || {
+                let _ = io::copy(&mut stdout, &mut from_child);
+                let _ = from_child.shutdown(Shutdown::Write);
+            });
+            (child, Socket::Tcp(Async::new(ours)?))
+        };
+
+        let stderr = Arc::new(Mutex::new(Vec::new()));
+        let mut pipe = child.stderr.take().unwrap();
+        let captured = stderr.clone();
+        let (closed_tx, closed_rx) = oneshot::channel::<()>();
+        thread::spawn(move || {
+            let _closed = closed_tx;
+            let mut buf = [0; 1024];
+            while let Ok(n) = pipe.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                let mut captured = captured.lock().unwrap();
+                captured.extend_from_slice(&buf[..n]);
+                let excess = captured.len().saturating_sub(STDERR_LIMIT);
+                captured.drain(..excess);
+            }
+        });
+        let stderr_closed = Mutex::new(Some(closed_rx));
+        Ok((ProxyChild { child, stderr, stderr_closed }, socket))
+    }
+}
+
+impl Drop for ProxyChild {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Bytes in flight from one side of the bridge to the other.
+struct Buffer {
+    data: Box<[u8]>,
+    start: usize,
+    end: usize,
+    eof: bool,
+}
+
+impl Buffer {
+    fn new() -> Buffer {
+        Buffer {
+            data: vec![0; BRIDGE_BUF_SIZE].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            eof: false,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+struct BridgeState {
+    stream: Box<dyn AsyncStream>,
+    /// libssh2 -> stream.
+    outgoing: Buffer,
+    /// stream -> libssh2.
+    incoming: Buffer,
+    closed: bool,
+}
+
+/// Shuttles bytes between an arbitrary stream and our end of the socket pair.
+///
+/// There is no background task: the bridge is pumped by whichever task is
+/// currently waiting on, or has just finished, a libssh2 operation.
+struct Bridge {
+    socket: Socket,
+    state: Mutex<BridgeState>,
+}
+
+impl Bridge {
+    /// Moves as many bytes as possible in both directions.
+    ///
+    /// Returns `Ready(Ok(()))` once everything libssh2 wrote so far has been
+    /// handed to the stream, and `Pending` otherwise. Wakers are registered
+    /// with whatever prevented further progress either way.
+    fn poll_pump(&self, cx: &mut Context<'_>, inbound: &Mutex<Inbound>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        let BridgeState {
+            stream,
+            outgoing,
+            incoming,
+            closed,
+        } = &mut *state;
+
+        loop {
+            let mut progress = false;
+
+            // libssh2 -> stream
+            if outgoing.is_empty() && !outgoing.eof {
+                match self.socket.poll_read(cx, &mut outgoing.data) {
+                    Poll::Ready(Ok(0)) => outgoing.eof = true,
+                    Poll::Ready(Ok(n)) => {
+                        outgoing.start = 0;
+                        outgoing.end = n;
+                        progress = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => {}
+                }
+            }
+            if !outgoing.is_empty() {
+                match Pin::new(&mut **stream)
+                    .poll_write(cx, &outgoing.data[outgoing.start..outgoing.end])
+                {
+                    Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                    Poll::Ready(Ok(n)) => {
+                        outgoing.start += n;
+                        progress = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => {}
+                }
+            } else if outgoing.eof && !*closed {
+                ready!(Pin::new(&mut **stream).poll_close(cx))?;
+                *closed = true;
+            }
+
+            // stream -> libssh2
+            if incoming.is_empty() && !incoming.eof {
+                match Pin::new(&mut **stream).poll_read(cx, &mut incoming.data) {
+                    Poll::Ready(Ok(0)) => {
+                        incoming.eof = true;
+                        self.socket.shutdown(Shutdown::Write)?;
+                    }
+                    Poll::Ready(Ok(n)) => {
+                        inbound.lock().unwrap().arrived();
+                        incoming.start = 0;
+                        incoming.end = n;
+                        progress = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => {}
+                }
+            }
+            if !incoming.is_empty() {
+                match self
+                    .socket
+                    .poll_write(cx, &incoming.data[incoming.start..incoming.end])
+                {
+                    Poll::Ready(Ok(n)) => {
+                        incoming.start += n;
+                        progress = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => {}
+                }
+            }
+
+            if !progress {
+                break;
+            }
+        }
+
+        if outgoing.is_empty() {
+            Pin::new(&mut **stream).poll_flush(cx)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Wakes every task waiting on a transport.
+///
+/// The socket and the bridged stream only remember the waker they were last
+/// polled with, so tasks sharing a session would steal each other's wakeups.
+/// Instead they are always polled with a waker that wakes every task that
+/// registered itself here.
+#[derive(Default)]
+struct Dispatcher {
+    waiters: Mutex<Vec<Waker>>,
+}
+
+impl Dispatcher {
+    fn register(&self, waker: &Waker) {
+        let mut waiters = self.waiters.lock().unwrap();
+        if !waiters.iter().any(|w| w.will_wake(waker)) {
+            waiters.push(waker.clone());
+        }
+    }
+
+    /// Wakes every waiter.
+    fn wake_all(&self) {
+        for waker in mem::take(&mut *self.waiters.lock().unwrap()) {
+            waker.wake();
+        }
+    }
+
+    /// Wakes every waiter except `except`.
+    fn wake_others(&self, except: &Waker) {
+        let waiters = mem::take(&mut *self.waiters.lock().unwrap());
+        for waker in waiters {
+            if !waker.will_wake(except) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl ArcWake for Dispatcher {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.wake_all();
+    }
+}
+
+/// The lock libssh2 calls on a session are made under, see
+/// [`Transport::poll_lock`]. Releasing it wakes the tasks waiting for it.
+pub(crate) struct CallLock<'a> {
+    transport: &'a Transport,
+    guard: Option<MutexGuard<'a, ()>>,
+}
+
+impl<'a> CallLock<'a> {
+    fn new(transport: &'a Transport, guard: MutexGuard<'a, ()>) -> CallLock<'a> {
+        CallLock {
+            transport,
+            guard: Some(guard),
+        }
+    }
+}
+
+impl Drop for CallLock<'_> {
+    fn drop(&mut self) {
+        // Unlock first, so that the woken tasks can take the lock.
+        self.guard = None;
+        self.transport.lock_waiters.wake_all();
+    }
+}
+
+impl CallLock<'_> {
+    /// See [`Transport::call`].
+    pub(crate) fn call<T>(
+        self,
+        session: &ssh2::Session,
+        f: impl FnOnce() -> T,
+    ) -> (T, BlockDirections) {
+        #[cfg(unix)]
+        let _receiving = Receiving::new(&self.transport.received);
+        let result = f();
+        (result, session.block_directions())
+    }
+
+    /// Notices data that arrived in libssh2's socket since the last check,
+    /// whether libssh2 has read it since or not. This takes the lock, as
+    /// libssh2 reading in between would move bytes from one count to the
+    /// other.
+    pub(crate) fn check_inbound(&self) -> io::Result<()> {
+        let transport = self.transport;
+        let total = transport.received.load(Ordering::Relaxed) + transport.socket.pending()? as u64;
+        let mut inbound = transport.inbound.lock().unwrap();
+        if total > inbound.total {
+            inbound.arrived();
+        }
+        inbound.total = total;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+thread_local! {
+    /// The count of the transport the libssh2 call running on this thread
+    /// is made for, see [`recv`].
+    static RECEIVED: Cell<*const AtomicU64> = const { Cell::new(ptr::null()) };
+}
+
+/// Points [`RECEIVED`] at a transport's count for the length of a call.
+#[cfg(unix)]
+struct Receiving;
+
+#[cfg(unix)]
+impl Receiving {
+    fn new(received: &AtomicU64) -> Receiving {
+        RECEIVED.with(|current| current.set(received));
+        Receiving
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Receiving {
+    fn drop(&mut self) {
+        RECEIVED.with(|current| current.set(ptr::null()));
+    }
+}
+
+#[cfg(unix)]
+extern "C" {
+    // Not bound by libssh2-sys.
+    fn libssh2_session_callback_set(
+        session: *mut libssh2_sys::LIBSSH2_SESSION,
+        cbtype: libc::c_int,
+        callback: *mut c_void,
+    ) -> *mut c_void;
+}
+
+#[cfg(unix)]
+const LIBSSH2_CALLBACK_RECV: libc::c_int = 6;
+
+/// Makes libssh2 read its socket through [`recv`], so that everything it
+/// receives is counted, see [`CallLock::check_inbound`].
+#[cfg(unix)]
+pub(crate) fn count_received(session: &ssh2::Session) {
+    let mut raw = session.raw();
+    // Safety: the callback has the signature libssh2 expects for
+    // `LIBSSH2_CALLBACK_RECV`.
+    unsafe {
+        libssh2_session_callback_set(
+            &mut *raw,
+            LIBSSH2_CALLBACK_RECV,
+            recv as *const () as *mut c_void,
+        );
+    }
+}
+
+/// libssh2's own socket read plus counting what it returns for the
+/// transport the call is made for. Reads outside of a call, which libssh2
+/// makes e.g. when a channel is freed, are not counted.
+#[cfg(unix)]
+extern "C" fn recv(
+    socket: libc::c_int,
+    buffer: *mut c_void,
+    length: libc::size_t,
+    flags: libc::c_int,
+    _abstract: *mut *mut c_void,
+) -> libc::ssize_t {
+    // Safety: libssh2 hands over a buffer of `length` bytes.
+    let rc = unsafe { libc::recv(socket, buffer, length, flags) };
+    if rc < 0 {
+        // Negated, and EWOULDBLOCK taken for EAGAIN, like libssh2 does.
+        let errno = io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO);
+        let errno = if errno == libc::EWOULDBLOCK { libc::EAGAIN } else { errno };
+        return -(errno as libc::ssize_t);
+    }
+    RECEIVED.with(|current| {
+        // Safety: `Receiving` only points it at a transport that is
+        // borrowed for the length of the call.
+        if let Some(received) = unsafe { current.get().as_ref() } {
+            received.fetch_add(rc as u64, Ordering::Relaxed);
+        }
+    });
+    rc
+}
+
+/// What has been seen of the data arriving from the peer, see
+/// [`CallLock::check_inbound`].
+struct Inbound {
+    /// When data was last seen arriving.
+    at: Instant,
+    /// How many bytes libssh2's socket had received at the last check.
+    total: u64,
+}
+
+impl Inbound {
+    fn arrived(&mut self) {
+        self.at = Instant::now();
+    }
+}
+
+/// The connection a session's libssh2 socket is attached to.
+///
+/// Either a plain TCP socket handed to libssh2 directly, or any byte stream
+/// bridged to libssh2 through a local socket pair.
+pub(crate) struct Transport {
+    socket: Socket,
+    bridge: Option<Bridge>,
+    child: Option<ProxyChild>,
+    inbound: Mutex<Inbound>,
+    /// How many bytes libssh2 has read from its socket, see
+    /// [`count_received`]. Stays 0 on Windows, where only the unread bytes
+    /// are counted.
+    received: AtomicU64,
+    dead: AtomicBool,
+    dead_subscribers: Mutex<Vec<oneshot::Sender<()>>>,
+    /// The server, for error reports.
+    peer: Mutex<Option<String>>,
+    /// The session's default operation timeout.
+    timeout: Mutex<Option<Duration>>,
+    interrupted: Interrupted,
+    /// Serializes libssh2 calls, see [`call`](Transport::call).
+    calls: Mutex<()>,
+    /// Tasks waiting for `calls`, see [`poll_lock`](Transport::poll_lock).
+    lock_waiters: Dispatcher,
+    dispatcher: Arc<Dispatcher>,
+    /// Wakes everything registered with `dispatcher`.
+    waker: Waker,
+}
+
+impl Transport {
+    fn new(socket: Socket, bridge: Option<Bridge>, child: Option<ProxyChild>) -> Transport {
+        let dispatcher = Arc::new(Dispatcher::default());
+        Transport {
+            socket,
+            bridge,
+            child,
+            inbound: Mutex::new(Inbound {
+                at: Instant::now(),
+                total: 0,
+            }),
+            received: AtomicU64::new(0),
+            dead: AtomicBool::new(false),
+            dead_subscribers: Mutex::new(Vec::new()),
+            peer: Mutex::new(None),
+            timeout: Mutex::new(None),
+            interrupted: Interrupted::default(),
+            calls: Mutex::new(()),
+            lock_waiters: Dispatcher::default(),
+            waker: task::waker(dispatcher.clone()),
+            dispatcher,
+        }
+    }
+
+    pub(crate) fn tcp(stream: Async<TcpStream>) -> Transport {
+        let peer = stream.get_ref().peer_addr().ok().map(|addr| addr.to_string());
+        let transport = Transport::new(Socket::Tcp(stream), None, None);
+        *transport.peer.lock().unwrap() = peer;
+        transport
+    }
+
+    pub(crate) fn command(command: Command) -> io::Result<Transport> {
+        let (child, socket) = ProxyChild::spawn(command)?;
+        Ok(Transport::new(socket, None, Some(child)))
+    }
+
+    /// Gives the proxy command up to `timeout` to finish writing to stderr,
+    /// so that its last words make it into an error report.
+    pub(crate) async fn settle_proxy_stderr(&self, timeout: Duration) {
+        let closed = match &self.child {
+            Some(child) => child.stderr_closed.lock().unwrap().take(),
+            None => None,
+        };
+        if let Some(closed) = closed {
+            future::select(closed, Timer::after(timeout)).await;
+        }
+    }
+
+    /// The server at the other end, as shown in error reports.
+    pub(crate) fn peer(&self) -> Option<String> {
+        self.peer.lock().unwrap().clone()
+    }
+
+    /// Names the server by the host it was connected to rather than its
+    /// address, or at all if the transport is not a TCP socket.
+    pub(crate) fn set_peer(&self, peer: String) {
+        *self.peer.lock().unwrap() = Some(peer);
+    }
+
+    /// The timeout of an operation started now, see
+    /// [`with_timeout`](crate::with_timeout).
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        timeout::effective(*self.timeout.lock().unwrap())
+    }
+
+    pub(crate) fn default_timeout(&self) -> Option<Duration> {
+        *self.timeout.lock().unwrap()
+    }
+
+    pub(crate) fn set_default_timeout(&self, timeout: Option<Duration>) {
+        *self.timeout.lock().unwrap() = timeout;
+    }
+
+    /// Tracks interrupted session-level operations, see [`Interrupted`].
+    pub(crate) fn interrupted(&self) -> &Interrupted {
+        &self.interrupted
+    }
+
+    /// The tail of the proxy command's stderr, if this transport is one.
+    pub(crate) fn proxy_stderr(&self) -> Option<String> {
+        let child = self.child.as_ref()?;
+        let stderr = child.stderr.lock().unwrap();
+        Some(String::from_utf8_lossy(&stderr).into_owned())
+    }
+
+    pub(crate) fn bridged<S>(stream: S) -> io::Result<Transport>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (socket, bridge_socket) = socket_pair()?;
+        let bridge = Bridge {
+            socket: bridge_socket,
+            state: Mutex::new(BridgeState {
+                stream: Box::new(stream),
+                outgoing: Buffer::new(),
+                incoming: Buffer::new(),
+                closed: false,
+            }),
+        };
+        Ok(Transport::new(socket, Some(bridge), None))
+    }
+
+    /// Makes a non-blocking libssh2 call and returns the directions it was
+    /// blocked in, with no other call on the session in between.
+    ///
+    /// ssh2 already locks the session for each call, but a call made by
+    /// another task between ours and reading the directions would make us
+    /// wait for the wrong thing.
+    ///
+    /// This blocks for as long as another call holds the lock, e.g. a
+    /// keyboard-interactive prompt is being answered, so tasks take the lock
+    /// with [`poll_lock`](Transport::poll_lock) instead.
+    pub(crate) fn call<T>(
+        &self,
+        session: &ssh2::Session,
+        f: impl FnOnce() -> T,
+    ) -> (T, BlockDirections) {
+        let lock = self.calls.lock().unwrap_or_else(PoisonError::into_inner);
+        CallLock::new(self, lock).call(session, f)
+    }
+
+    /// Takes the lock libssh2 calls are made under.
+    ///
+    /// Most calls only hold it briefly, but a keyboard-interactive attempt
+    /// holds it while the prompter answers, which may take long. Either way
+    /// the task waits to be woken when the lock is released rather than
+    /// blocking its thread.
+    pub(crate) fn poll_lock(&self, cx: &mut Context<'_>) -> Poll<CallLock<'_>> {
+        let try_lock = || match self.calls.try_lock() {
+            Ok(lock) => Some(CallLock::new(self, lock)),
+            Err(TryLockError::Poisoned(e)) => Some(CallLock::new(self, e.into_inner())),
+            Err(TryLockError::WouldBlock) => None,
+        };
+        if let Some(lock) = try_lock() {
+            return Poll::Ready(lock);
+        }
+        self.lock_waiters.register(cx.waker());
+        // The lock may have been released before registering.
+        match try_lock() {
+            Some(lock) => Poll::Ready(lock),
+            None => Poll::Pending,
+        }
+    }
+
+    /// See [`poll_lock`](Transport::poll_lock).
+    pub(crate) async fn lock(&self) -> CallLock<'_> {
+        poll_fn(|cx| self.poll_lock(cx)).await
+    }
+
+    /// Polls `f` with the dispatcher's waker after registering the calling
+    /// task with it.
+    fn dispatch<T>(
+        &self,
+        cx: &mut Context<'_>,
+        f: impl FnOnce(&mut Context<'_>) -> Poll<T>,
+    ) -> Poll<T> {
+        // Register first so that a wakeup between polling and registering
+        // is not lost.
+        self.dispatcher.register(cx.waker());
+        f(&mut Context::from_waker(&self.waker))
+    }
+
+    /// Forwards everything libssh2 has written so far to the bridged stream.
+    pub(crate) fn poll_flush(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &self.bridge {
+            Some(bridge) => self.dispatch(cx, |cx| bridge.poll_pump(cx, &self.inbound)),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// See [`poll_flush`](Transport::poll_flush).
+    pub(crate) async fn flush(&self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_flush(cx)).await
+    }
+
+    /// Waits until the socket is ready in the `directions` libssh2 was
+    /// blocked in, as returned by [`call`](Transport::call).
+    ///
+    /// Every task waiting on the transport is woken when the socket becomes
+    /// ready, and again when one of them gets through: reading on behalf of
+    /// one channel may have buffered the packets another one waits for, and
+    /// the socket would not become readable for those.
+    ///
+    /// `retried` tracks whether the caller already retried right away after
+    /// libssh2 blocked without waiting on the socket; it starts out `false`
+    /// and is reset once the wait ends.
+    pub(crate) fn poll_wait(
+        &self,
+        cx: &mut Context<'_>,
+        directions: &BlockDirections,
+        retried: &mut bool,
+    ) -> Poll<io::Result<()>> {
+        if self.is_dead() {
+            return Poll::Ready(Err(dead_error()));
+        }
+        if let Poll::Ready(Err(e)) = self.poll_flush(cx) {
+            return Poll::Ready(Err(e));
+        }
+        if let BlockDirections::None = directions {
+            // libssh2 also reports EAGAIN without waiting on the socket,
+            // e.g. when a read on another channel of the session took the
+            // packet it was waiting for. That packet is buffered by now, so
+            // try again right away, but only once: should libssh2 keep
+            // at it, wait for the socket or for another task to get through
+            // rather than spin.
+            if !*retried {
+                *retried = true;
+                return Poll::Ready(Ok(()));
+            }
+        }
+        let poll = self.dispatch(cx, |cx| match directions {
+            BlockDirections::Inbound | BlockDirections::None => {
+                self.socket.poll_readable(cx)
+            },
+            BlockDirections::Outbound => self.socket.poll_writable(cx),
+            BlockDirections::Both => {
+                match self.socket.poll_readable(cx) {
+                    Poll::Pending => self.socket.poll_writable(cx),
+                    ready => ready,
+                }
+            },
+        });
+        if poll.is_ready() {
+            *retried = false;
+            self.dispatcher.wake_others(cx.waker());
+        }
+        poll
+    }
+
+    /// See [`poll_wait`](Transport::poll_wait).
+    pub(crate) async fn wait(&self, directions: &BlockDirections, retried: &mut bool) -> Result<(), Error> {
+        poll_fn(|cx| self.poll_wait(cx, directions, retried)).await?;
+        Ok(())
+    }
+
+    /// Moves whatever the bridged stream has received so far over to
+    /// libssh2's socket, without waiting for anything.
+    pub(crate) fn pump(&self) {
+        if let Some(bridge) = &self.bridge {
+            let _ = bridge.poll_pump(&mut Context::from_waker(&self.waker), &self.inbound);
+        }
+    }
+
+    /// When data from the peer was last seen arriving, as of the last
+    /// [`check_inbound`](CallLock::check_inbound) or bridge pump.
+    pub(crate) fn last_received(&self) -> Instant {
+        self.inbound.lock().unwrap().at
+    }
+
+    pub(crate) fn is_dead(&self) -> bool {
+        self.dead.load(Ordering::Acquire)
+    }
+
+    /// Gives up on the connection: pending and future waits fail and
+    /// subscribers are notified.
+    pub(crate) fn mark_dead(&self) {
+        if self.dead.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        // Wakes up every task waiting on the socket.
+        let _ = self.socket.shutdown(Shutdown::Both);
+        for subscriber in self.dead_subscribers.lock().unwrap().drain(..) {
+            let _ = subscriber.send(());
+        }
+    }
+
+    /// Resolves with `Ok` once the transport is marked dead, or with
+    /// `Err(Canceled)` if it is dropped before.
+    pub(crate) fn dead(&self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        let mut subscribers = self.dead_subscribers.lock().unwrap();
+        if self.is_dead() {
+            let _ = tx.send(());
+        } else {
+            subscribers.push(tx);
+        }
+        rx
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn as_raw_socket(&self) -> RawSocket {
+        self.socket.as_raw_socket()
+    }
+}
+
+/// The error waits on a transport fail with once it has been marked dead.
+pub(crate) fn dead_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "server stopped responding",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, net::TcpListener};
+
+    #[cfg(unix)]
+    #[test]
+    fn data_read_by_libssh2_is_noticed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let transport = Transport::tcp(Async::new(stream).unwrap());
+        let session = ssh2::Session::new().unwrap();
+        let check = || {
+            let lock = futures::executor::block_on(transport.lock());
+            lock.check_inbound().unwrap();
+            transport.last_received()
+        };
+        // Stands in for libssh2 reading on behalf of a channel.
+        let read = |len: usize| {
+            let mut buf = vec![0u8; len];
+            let fd = transport.as_raw_fd();
+            let (n, _) = transport.call(&session, || {
+                recv(fd, buf.as_mut_ptr().cast(), len, 0, ptr::null_mut())
+            });
+            assert_eq!(n, len as libc::ssize_t);
+        };
+
+        let start = check();
+        peer.write_all(b"reply").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let unread = check();
+        assert!(unread > start);
+
+        // Reading what was already seen is not mistaken for anything
+        // arriving.
+        read(5);
+        assert_eq!(check(), unread);
+
+        peer.write_all(b"reply").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        read(5);
+        assert!(check() > unread);
+    }
+
+    #[test]
+    fn released_lock_wakes_waiters() {
+        struct Flag(AtomicBool);
+
+        impl ArcWake for Flag {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let transport = Transport::tcp(Async::new(stream).unwrap());
+        let session = ssh2::Session::new().unwrap();
+
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = task::waker(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        thread::scope(|scope| {
+            let session = &session;
+            let transport = &transport;
+            scope.spawn(move || {
+                transport.call(session, || {
+                    locked_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                })
+            });
+            locked_rx.recv().unwrap();
+            assert!(transport.poll_lock(&mut cx).is_pending());
+            assert!(!flag.0.load(Ordering::SeqCst));
+            release_tx.send(()).unwrap();
+        });
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert!(transport.poll_lock(&mut cx).is_ready());
+    }
+}
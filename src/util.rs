@@ -1,89 +1,513 @@
-use crate::Error;
-use async_io::Async;
-use std::{io, 
-    net::TcpStream,
+use crate::{
+    error::{interrupted_error, OperationError},
+    timeout::timeout_error,
+    transport::Transport,
+    Error,
+};
+use async_io::Timer;
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Mutex,
     task::{Context, Poll},
+    thread,
+};
+use futures::{
+    channel::oneshot,
+    future::{self, Either},
+    prelude::*,
 };
-use futures::{future, ready};
-use futures_util;
 use ssh2::{self, BlockDirections, ErrorCode};
-use libssh2_sys;
 
-fn would_block(e: &ssh2::Error) -> bool {
-    match e.code() {
-        ErrorCode::Session(e) if e == libssh2_sys::LIBSSH2_ERROR_EAGAIN => true,
-        _ => false
+pub fn would_block(e: &ssh2::Error) -> bool {
+    matches!(e.code(), ErrorCode::Session(e) if e == libssh2_sys::LIBSSH2_ERROR_EAGAIN)
+}
+
+/// Run a blocking closure on a helper thread without blocking the executor.
+pub async fn unblock<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = tx.send(f());
+    });
+    rx.await.expect("blocking task panicked")
+}
+
+/// Wait until the socket is ready in the direction libssh2 is blocked on.
+///
+/// See [`Transport::poll_wait`] for `retried`.
+pub async fn wait_for_session(
+    stream: &Transport,
+    directions: &BlockDirections,
+    retried: &mut bool,
+) -> Result<(), Error> {
+    stream.wait(directions, retried).await
+}
+
+/// Names an operation for error reports. The target is only formatted if
+/// the operation fails.
+#[derive(Clone, Copy)]
+pub struct Op<'a> {
+    name: &'static str,
+    target: Target<'a>,
+    interrupted: Option<&'a Interrupted>,
+    abandonable: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    None,
+    Path(&'a Path),
+    Arg(&'a str),
+    Addr(&'a str, u16),
+}
+
+impl<'a> Op<'a> {
+    pub fn new(name: &'static str) -> Op<'a> {
+        Op {
+            name,
+            target: Target::None,
+            interrupted: None,
+            abandonable: false,
+        }
+    }
+
+    /// The remote file the operation works on.
+    pub fn path(self, path: &'a Path) -> Op<'a> {
+        Op {
+            target: Target::Path(path),
+            ..self
+        }
+    }
+
+    /// A command, user name or similar the operation works on.
+    pub fn arg(self, arg: &'a str) -> Op<'a> {
+        Op {
+            target: Target::Arg(arg),
+            ..self
+        }
+    }
+
+    /// The address the operation connects to or listens on.
+    pub fn addr(self, host: &'a str, port: u16) -> Op<'a> {
+        Op {
+            target: Target::Addr(host, port),
+            ..self
+        }
+    }
+
+    /// The operation works on the libssh2 state of a channel, SFTP
+    /// subsystem or similar, so interrupting it only blocks further
+    /// operations on that object rather than on the whole session.
+    pub fn on(self, interrupted: &'a Interrupted) -> Op<'a> {
+        Op {
+            interrupted: Some(interrupted),
+            ..self
+        }
+    }
+
+    /// The operation only waits for incoming data and can be interrupted at
+    /// any point.
+    pub fn abandonable(self) -> Op<'a> {
+        Op {
+            abandonable: true,
+            ..self
+        }
+    }
+
+    fn target(&self) -> Option<String> {
+        match self.target {
+            Target::None => None,
+            Target::Path(path) => Some(path.display().to_string()),
+            Target::Arg(arg) => Some(arg.to_owned()),
+            Target::Addr(host, port) => Some(host_port(host, port)),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.target() {
+            Some(target) => format!("{} `{}`", self.name, target),
+            None => self.name.to_owned(),
+        }
+    }
+
+    /// Attaches the operation, its target and the transport's peer to `e`.
+    pub fn error(self, stream: &Transport, e: Error) -> Error {
+        OperationError::new(self.name, self.target(), stream.peer(), e).into()
+    }
+}
+
+/// Remembers an operation whose future was dropped while libssh2 was
+/// halfway through it.
+///
+/// libssh2 keeps the progress of a request in the session, channel or SFTP
+/// state and expects the very same call to be repeated until it completes.
+/// Until that happens, other operations on the object fail with an error of
+/// kind [`ErrorKind::Interrupted`](crate::ErrorKind::Interrupted). A request
+/// that is halfway through sending a packet blocks the whole session.
+#[derive(Default)]
+pub struct Interrupted(Mutex<Option<String>>);
+
+impl Interrupted {
+    /// Fails unless nothing was interrupted or `op` is the interrupted
+    /// operation being retried, which libssh2 then resumes.
+    fn check(&self, op: &Op<'_>) -> io::Result<()> {
+        let mut interrupted = self.0.lock().unwrap();
+        match interrupted.take() {
+            None => Ok(()),
+            Some(previous) if previous == op.describe() => Ok(()),
+            Some(previous) => {
+                let e = interrupted_error(&previous);
+                *interrupted = Some(previous);
+                Err(e)
+            }
+        }
+    }
+
+    fn set(&self, op: &Op<'_>) {
+        *self.0.lock().unwrap() = Some(op.describe());
+    }
+}
+
+/// Records `op` as interrupted if it is dropped while armed.
+struct InterruptGuard<'a> {
+    op: Op<'a>,
+    stream: &'a Transport,
+    /// Where libssh2 was blocked when the operation was last tried.
+    directions: BlockDirections,
+    armed: bool,
+}
+
+impl Drop for InterruptGuard<'_> {
+    fn drop(&mut self) {
+        // A dead session fails everything anyway.
+        if !self.armed || self.stream.is_dead() {
+            return;
+        }
+        match self.directions {
+            // Nothing else can be sent before the rest of the packet.
+            BlockDirections::Outbound | BlockDirections::Both => {
+                self.stream.interrupted().set(&self.op)
+            }
+            _ if self.op.abandonable => {}
+            _ => self.op.interrupted.unwrap_or(self.stream.interrupted()).set(&self.op),
+        }
+    }
+}
+
+/// Formats an address like `host:port`, bracketing IPv6 literals.
+pub fn host_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Runs `fut`, failing with a timeout error if it takes longer than the
+/// operation timeout. libssh2 cannot abandon a request half-way, so the
+/// session is closed when that happens, unless `op` only waits for incoming
+/// data.
+pub async fn limit<R>(
+    stream: &Transport,
+    op: Op<'_>,
+    fut: impl Future<Output = Result<R, Error>>,
+) -> Result<R, Error> {
+    let timeout = match stream.timeout() {
+        Some(timeout) => timeout,
+        None => return fut.await,
+    };
+    futures_util::pin_mut!(fut);
+    match future::select(fut, Timer::after(timeout)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => {
+            if !op.abandonable {
+                stream.mark_dead();
+            }
+            Err(timeout_error(timeout).into())
+        }
     }
 }
 
 pub async fn run_ssh2_fn<R, F: FnMut() -> Result<R, ssh2::Error>>(
-    stream: &Async<TcpStream>,
+    stream: &Transport,
     session: &ssh2::Session,
+    op: Op<'_>,
     mut cb: F,
 ) -> Result<R, Error> {
-
-    loop {
-        match cb() {
-            Ok(v) => return Ok(v),
-            Err(e) if would_block(&e) => {
-                match session.block_directions() {
-                    BlockDirections::Inbound => {
-                        stream.readable().await?
-                    },
-                    BlockDirections::Outbound => {
-                        stream.writable().await?
-                    },
-                    BlockDirections::Both => {
-                        let readable = stream.readable();
-                        let writable = stream.writable();
-                        futures_util::pin_mut!(readable);
-                        futures_util::pin_mut!(writable);
-                        let (ready,_) = future::select(readable, writable).await.factor_first();
-                        ready?
-                    },
-                    BlockDirections::None => {
-                        // This should not happen - libssh2 has already reported that it would block
-                        panic!("libssh2 reports EAGAIN but is not blocked");
-                    },
+    let result = async {
+        stream.interrupted().check(&op)?;
+        if let Some(interrupted) = op.interrupted {
+            interrupted.check(&op)?;
+        }
+        let mut guard = InterruptGuard {
+            op,
+            stream,
+            directions: BlockDirections::None,
+            armed: false,
+        };
+        let mut retried = false;
+        loop {
+            let (result, directions) = stream.lock().await.call(session, &mut cb);
+            guard.directions = directions;
+            match result {
+                Ok(v) => {
+                    guard.armed = false;
+                    stream.flush().await?;
+                    return Ok(v)
+                },
+                Err(e) if would_block(&e) => {
+                    guard.armed = true;
+                    if let Err(e) = wait_for_session(stream, &guard.directions, &mut retried).await {
+                        guard.armed = false;
+                        return Err(e);
+                    }
+                },
+                Err(e) => {
+                    guard.armed = false;
+                    return Err(Error::from(e))
                 }
-            },
-            Err(e) => return Err(Error::from(e))
+            }
         }
-    }
+    };
+    limit(stream, op, result).await.map_err(|e| op.error(stream, e))
 }
 
 /// Perform libssh2 asynchronous I/O Operation
+///
+/// `timer` runs from the first time the operation has to wait until it
+/// completes. Nothing is lost if it times out: reads can simply be retried,
+/// writes have to be retried with the same data.
 pub fn poll_ssh2_io_op<T, F: FnMut() -> Result<T,io::Error>>(
     cx: &mut Context<'_>,
-    stream: &Async<TcpStream>,
+    stream: &Transport,
     session: &ssh2::Session,
-    mut op: F,
+    timer: &mut Option<Timer>,
+    op: F,
 ) -> Poll<Result<T,io::Error>> {
+    let poll = poll_io_op(cx, stream, session, timer, op);
+    if poll.is_ready() {
+        *timer = None;
+    }
+    poll
+}
 
+fn poll_io_op<T, F: FnMut() -> Result<T,io::Error>>(
+    cx: &mut Context<'_>,
+    stream: &Transport,
+    session: &ssh2::Session,
+    timer: &mut Option<Timer>,
+    mut op: F,
+) -> Poll<Result<T,io::Error>> {
+    let mut retried = false;
     loop {
-        match op() {
-            Ok(result) => return Poll::Ready(Ok(result)),
+        let (result, directions) = futures::ready!(stream.poll_lock(cx)).call(session, &mut op);
+        match result {
+            Ok(result) => {
+                // libssh2 already took the data, so don't hold the result back
+                // on a slow bridged stream; whatever is left is forwarded the
+                // next time the transport is polled.
+                if let Poll::Ready(Err(e)) = stream.poll_flush(cx) {
+                    return Poll::Ready(Err(e));
+                }
+                return Poll::Ready(Ok(result))
+            },
             Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                match session.block_directions() {
-                    BlockDirections::Inbound => {
-                        ready!(stream.poll_readable(cx))?;
-                    },
-                    BlockDirections::Outbound => {
-                        ready!(stream.poll_writable(cx))?;
-                    },
-                    BlockDirections::Both => {
-                        match stream.poll_readable(cx) {
-                            Poll::Pending => ready!(stream.poll_writable(cx))?,
-                            Poll::Ready(_) => {}
-                        };
-                    },
-                    BlockDirections::None => {
-                        // This should not happen - libssh2 has already reported that it would block
-                        panic!("libssh2 reports EAGAIN but is not blocked");
-                    },
+                match stream.poll_wait(cx, &directions, &mut retried) {
+                    Poll::Ready(result) => result?,
+                    Poll::Pending => {
+                        if let Some(timeout) = stream.timeout() {
+                            let timer = timer.get_or_insert_with(|| Timer::after(timeout));
+                            if Pin::new(timer).poll(cx).is_ready() {
+                                return Poll::Ready(Err(timeout_error(timeout)));
+                            }
+                        }
+                        return Poll::Pending;
+                    }
                 }
             },
             Err(e) => return Poll::Ready(Err(e))
         }
     }
 }
+
+/// Implements tokio's `poll_read` in terms of a futures-style `read`.
+#[cfg(feature = "tokio")]
+pub fn poll_read_buf(
+    buf: &mut tokio::io::ReadBuf<'_>,
+    read: impl FnOnce(&mut [u8]) -> Poll<io::Result<usize>>,
+) -> Poll<io::Result<()>> {
+    let n = futures::ready!(read(buf.initialize_unfilled()))?;
+    buf.advance(n);
+    Poll::Ready(Ok(()))
+}
+
+/// Standard base64 with padding, as used in known_hosts files and (without
+/// padding) in OpenSSH fingerprints.
+pub fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decodes standard base64, with or without padding. Returns `None` on any
+/// invalid input.
+pub fn base64_decode(data: &str) -> Option<Vec<u8>> {
+    let data = data.trim_end_matches('=').as_bytes();
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &c in data {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = acc << 6 | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    // Leftover bits must be padding, and a single leftover character can't
+    // encode anything.
+    if bits >= 6 || acc & ((1 << bits) - 1) != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+/// Matches `s` against an OpenSSH style pattern where `*` matches any
+/// sequence of characters and `?` any single character.
+pub fn wildcard_match(pattern: &str, s: &str) -> bool {
+    let (p, s) = (pattern.as_bytes(), s.as_bytes());
+    let (mut pi, mut si) = (0, 0);
+    let mut star = None;
+    while si < s.len() {
+        match p.get(pi) {
+            Some(b'*') => {
+                star = Some((pi, si));
+                pi += 1;
+            }
+            Some(&c) if c == b'?' || c == s[si] => {
+                pi += 1;
+                si += 1;
+            }
+            _ => match star {
+                Some((sp, ss)) => {
+                    pi = sp + 1;
+                    si = ss + 1;
+                    star = Some((sp, ss + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    p[pi..].iter().all(|&c| c == b'*')
+}
+
+/// The current user's home directory, or an empty path if it is unknown.
+pub fn home_dir() -> PathBuf {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .unwrap_or_default()
+        .into()
+}
+
+/// The name of the local user, or an empty string if it is unknown.
+pub fn local_user() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_io::Async;
+    use futures::{
+        executor::block_on,
+        task::{waker, ArcWake},
+    };
+    use std::{
+        net::{TcpListener, TcpStream},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    /// A transport to a peer that never says anything.
+    fn silent_transport() -> (TcpListener, Transport) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let transport = Transport::tcp(Async::new(stream).unwrap());
+        transport.set_default_timeout(Some(Duration::from_millis(50)));
+        (listener, transport)
+    }
+
+    #[test]
+    fn abandonable_timeout_keeps_session() {
+        let (_listener, transport) = silent_transport();
+        let never = future::pending::<Result<(), Error>>;
+        let op = Op::new("wait_eof").abandonable();
+        let waited = block_on(limit(&transport, op, never()));
+        assert!(waited.unwrap_err().is_timeout());
+        assert!(!transport.is_dead());
+
+        // Anything else may have left libssh2 halfway through a request.
+        let op = Op::new("channel_session");
+        let waited = block_on(limit(&transport, op, never()));
+        assert!(waited.unwrap_err().is_timeout());
+        assert!(transport.is_dead());
+    }
+
+    #[derive(Default)]
+    struct CountWakes(AtomicUsize);
+
+    impl ArcWake for CountWakes {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn blocked_without_direction_does_not_spin() {
+        let (_listener, transport) = silent_transport();
+        let session = ssh2::Session::new().unwrap();
+        let wakes = Arc::new(CountWakes::default());
+        let waker = waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // A fresh session blocks in no direction. The call is retried once
+        // right away, then the task waits for the silent socket.
+        let mut calls = 0;
+        let poll = poll_ssh2_io_op(&mut cx, &transport, &session, &mut None, || {
+            calls += 1;
+            Err::<(), _>(io::Error::from(io::ErrorKind::WouldBlock))
+        });
+        assert!(poll.is_pending());
+        assert_eq!(calls, 2);
+        assert_eq!(wakes.0.load(Ordering::Relaxed), 0);
+    }
+}
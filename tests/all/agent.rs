@@ -11,7 +11,7 @@ async fn smoke() {
     {
         let a = agent.identities().unwrap();
         let i1 = &a[0];
-        assert!(agent.userauth("foo", &i1).await.is_err());
+        assert!(agent.userauth("foo", i1).await.is_err());
     }
     agent.disconnect().await.unwrap();
 }
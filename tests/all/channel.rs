@@ -1,9 +1,15 @@
-use async_ssh2::Channel;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
+use async_io::Async;
+use async_ssh2::{Channel, ErrorKind, Session};
+use futures::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    FutureExt,
+};
 use std::{
+    error::Error as _,
     io::prelude::*,
     net::{TcpListener, TcpStream},
     thread,
+    time::Duration,
 };
 
 /// Consume all available stdout and stderr data.
@@ -31,8 +37,15 @@ async fn smoke() {
     fn must_be_send<T: Send>(_: &T) -> bool {
         true
     }
+    fn must_be_sync<T: Sync>(_: &T) -> bool {
+        true
+    }
     assert!(must_be_send(&channel));
     assert!(must_be_send(&channel.stream(0)));
+    assert!(must_be_sync(&channel));
+    assert!(must_be_sync(&channel.stream(0)));
+    assert!(must_be_send(&channel.stderr_handle()));
+    assert!(must_be_sync(&channel.stderr_handle()));
 
     channel.flush().await.unwrap();
     channel.exec("true").await.unwrap();
@@ -136,6 +149,114 @@ async fn setenv() {
     channel.close().await.unwrap();
 }
 
+#[tokio::test]
+async fn request_agent_forwarding() {
+    let sess = crate::authed_session().await;
+    let mut channel = sess.channel_session().await.unwrap();
+    channel.request_agent_forwarding().await.unwrap();
+    // Nothing connects to the socket, which libssh2 could not serve.
+    channel.exec("echo ${SSH_AUTH_SOCK:+set}").await.unwrap();
+    let mut output = String::new();
+    channel.read_to_string(&mut output).await.unwrap();
+    assert_eq!(output, "set\n");
+}
+
+#[tokio::test]
+async fn canceled() {
+    let sess = crate::authed_session().await;
+    let mut channel = sess.channel_session().await.unwrap();
+    // The server cannot have replied after a single poll.
+    assert!(channel.exec("echo foo").now_or_never().is_none());
+    let err = channel.setenv("FOO", "bar").await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Interrupted);
+    let err = channel.exec("true").await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Interrupted);
+
+    // Other channels are not affected.
+    sess.channel_session().await.unwrap();
+
+    channel.exec("echo foo").await.unwrap();
+    let _ = channel.wait_eof().now_or_never();
+    let (stdout, _) = consume_stdio(&mut channel).await;
+    assert_eq!(stdout, "foo\n");
+    channel.wait_eof().await.unwrap();
+}
+
+#[tokio::test]
+async fn many_concurrent_channels() {
+    let sess = crate::authed_session().await;
+    let runs = (0..32).map(|i| {
+        let sess = sess.clone();
+        async move {
+            let mut channel = sess.channel_session().await.unwrap();
+            channel
+                .exec(&format!("head -c {} /dev/zero", 64 * 1024 + i))
+                .await
+                .unwrap();
+            let mut stdout = Vec::new();
+            channel.read_to_end(&mut stdout).await.unwrap();
+            channel.wait_close().await.unwrap();
+            assert_eq!(stdout.len(), 64 * 1024 + i);
+            assert_eq!(channel.exit_status().unwrap(), 0);
+        }
+    });
+    futures::future::join_all(runs).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn many_concurrent_tasks() {
+    let sess = crate::authed_session().await;
+    let tasks = (0..24)
+        .map(|i| {
+            let sess = sess.clone();
+            tokio::spawn(async move {
+                let mut channel = sess.channel_session().await.unwrap();
+                channel.exec(&format!("seq 1 {}", 10_000 + i)).await.unwrap();
+                let mut stdout = String::new();
+                channel.read_to_string(&mut stdout).await.unwrap();
+                channel.wait_close().await.unwrap();
+                assert_eq!(stdout.lines().count(), 10_000 + i);
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        tokio::time::timeout(Duration::from_secs(60), task)
+            .await
+            .expect("a task was starved")
+            .unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn split_stdio() {
+    let sess = crate::authed_session().await;
+    let mut channel = sess.channel_session().await.unwrap();
+    channel
+        .exec("tee /dev/stderr | head -c 300000 >/dev/null; head -c 200000 /dev/zero")
+        .await
+        .unwrap();
+    let (mut stdin, mut stdout, mut stderr) = channel.split_stdio();
+    assert_eq!(stderr.id(), ssh2::EXTENDED_DATA_STDERR);
+
+    let writer = tokio::spawn(async move {
+        stdin.write_all(&[b'x'; 300_000]).await.unwrap();
+        stdin.close().await.unwrap();
+    });
+    let errors = tokio::spawn(async move {
+        let mut err = Vec::new();
+        stderr.read_to_end(&mut err).await.unwrap();
+        err
+    });
+    let mut out = Vec::new();
+    stdout.read_to_end(&mut out).await.unwrap();
+    writer.await.unwrap();
+    assert_eq!(out.len(), 200_000);
+    assert_eq!(errors.await.unwrap(), vec![b'x'; 300_000]);
+
+    channel.wait_close().await.unwrap();
+    assert_eq!(channel.exit_status().unwrap(), 0);
+}
+
 #[tokio::test]
 async fn direct() {
     let a = TcpListener::bind("127.0.0.1:0").unwrap();
@@ -143,7 +264,7 @@ async fn direct() {
     let t = thread::spawn(move || {
         let mut s = a.accept().unwrap().0;
         let mut b = [0, 0, 0];
-        s.read(&mut b).unwrap();
+        s.read_exact(&mut b).unwrap();
         assert_eq!(b, [1, 2, 3]);
         s.write_all(&[4, 5, 6]).unwrap();
     });
@@ -154,9 +275,61 @@ async fn direct() {
         .unwrap();
     channel.write_all(&[1, 2, 3]).await.unwrap();
     let mut r = [0, 0, 0];
-    channel.read(&mut r).await.unwrap();
+    channel.read_exact(&mut r).await.unwrap();
+    assert_eq!(r, [4, 5, 6]);
+    t.join().ok().unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn direct_streamlocal() {
+    use std::os::unix::net::UnixListener;
+
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("direct.sock");
+    let a = UnixListener::bind(&path).unwrap();
+    let t = thread::spawn(move || {
+        let mut s = a.accept().unwrap().0;
+        let mut b = [0, 0, 0];
+        s.read_exact(&mut b).unwrap();
+        assert_eq!(b, [1, 2, 3]);
+        s.write_all(&[4, 5, 6]).unwrap();
+    });
+    let sess = crate::authed_session().await;
+    let mut channel = sess
+        .channel_direct_streamlocal(path.to_str().unwrap())
+        .await
+        .unwrap();
+    channel.write_all(&[1, 2, 3]).await.unwrap();
+    let mut r = [0, 0, 0];
+    channel.read_exact(&mut r).await.unwrap();
     assert_eq!(r, [4, 5, 6]);
     t.join().ok().unwrap();
+
+    let long = format!("/tmp/{}", "x".repeat(200));
+    let err = match sess.channel_direct_streamlocal(&long).await {
+        Ok(_) => panic!("opened a channel to an overlong path"),
+        Err(e) => e,
+    };
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(err.operation(), Some("channel_direct_streamlocal"));
+    assert!(err.to_string().contains("at most 127"), "{}", err);
+}
+
+#[tokio::test]
+async fn direct_streamlocal_path_limit() {
+    // Rejected before anything is sent, so the peer needn't be a server.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = Async::<TcpStream>::connect(listener.local_addr().unwrap()).await.unwrap();
+    let mut sess = Session::new().unwrap();
+    sess.set_tcp_stream(stream).unwrap();
+    let path = format!("/{}", "x".repeat(127));
+    let err = match sess.channel_direct_streamlocal(&path).await {
+        Ok(_) => panic!("opened a channel to an overlong path"),
+        Err(e) => e,
+    };
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(err.target(), Some(path.as_str()));
 }
 
 #[tokio::test]
@@ -167,9 +340,9 @@ async fn forward() {
         .await
         .unwrap();
     let t = thread::spawn(move || {
-        let mut s = TcpStream::connect(&("127.0.0.1", port)).unwrap();
+        let mut s = TcpStream::connect(("127.0.0.1", port)).unwrap();
         let mut b = [0, 0, 0];
-        s.read(&mut b).unwrap();
+        s.read_exact(&mut b).unwrap();
         assert_eq!(b, [1, 2, 3]);
         s.write_all(&[4, 5, 6]).unwrap();
     });
@@ -177,7 +350,7 @@ async fn forward() {
     let mut channel = listen.accept().await.unwrap();
     channel.write_all(&[1, 2, 3]).await.unwrap();
     let mut r = [0, 0, 0];
-    channel.read(&mut r).await.unwrap();
+    channel.read_exact(&mut r).await.unwrap();
     assert_eq!(r, [4, 5, 6]);
     t.join().ok().unwrap();
 }
@@ -224,7 +397,7 @@ async fn exit_code_ignores_other_errors() {
     channel.wait_eof().await.unwrap();
     channel.close().await.unwrap();
     channel.wait_close().await.unwrap();
-    let longdescription: String = ::std::iter::repeat('a').take(300).collect();
+    let longdescription: String = std::iter::repeat_n('a', 300).collect();
     assert!(sess.disconnect(None, &longdescription, None).await.is_err()); // max len == 256
     assert!(channel.exit_status().unwrap() == 0);
 }
@@ -251,3 +424,53 @@ fn pty_modes_are_propagated() {
     assert!(out.contains("intr = y"), "mode was propagated");
 }
 */
+
+#[tokio::test]
+async fn exec_output() {
+    let sess = crate::authed_session().await;
+    let output = sess
+        .exec("head -c 1000000 /dev/zero >&2; echo foo; cat; exit 3")
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, b"foo\n");
+    assert_eq!(output.stderr.len(), 1_000_000);
+    assert_eq!(output.status, 3);
+    assert_eq!(output.signal, None);
+    assert!(!output.success());
+
+    let output = sess.exec("kill -TERM $$").await.unwrap();
+    assert_eq!(output.signal.as_deref(), Some("TERM"));
+    assert!(!output.success());
+
+    assert!(sess.exec("true").await.unwrap().success());
+}
+
+#[tokio::test]
+async fn exec_limited() {
+    let sess = crate::authed_session().await;
+    let output = sess.exec_limited("echo foo", 4).await.unwrap();
+    assert_eq!(output.stdout, b"foo\n");
+
+    let err = sess.exec_limited("yes", 1000).await.unwrap_err();
+    assert_eq!(err.operation(), Some("exec"));
+    assert_eq!(err.target(), Some("yes"));
+    assert!(err.to_string().contains("more than 1000 bytes"), "{}", err);
+    let source = err.source().unwrap();
+    assert!(source.to_string().contains("more than 1000 bytes"), "{}", source);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn tokio_io() {
+    let sess = crate::authed_session().await;
+    let mut channel = sess.channel_session().await.unwrap();
+    channel.exec("cat").await.unwrap();
+    let input = vec![b'x'; 100_000];
+    tokio::io::copy(&mut &input[..], &mut channel).await.unwrap();
+    channel.send_eof().await.unwrap();
+    let mut output = Vec::new();
+    tokio::io::AsyncReadExt::read_to_end(&mut channel, &mut output)
+        .await
+        .unwrap();
+    assert_eq!(output, input);
+}
@@ -0,0 +1,287 @@
+use async_ssh2::{DynamicForward, ForwardTarget, LocalForward, RemoteForward, Session};
+use futures::StreamExt;
+use std::{
+    io::prelude::*,
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+/// Starts a server that echoes everything back, uppercased.
+fn echo_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            thread::spawn(move || echo(stream.unwrap()));
+        }
+    });
+    port
+}
+
+fn echo<S: Read + Write>(mut stream: S) {
+    let mut buf = [0; 1024];
+    loop {
+        let n = stream.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        stream.write_all(&buf[..n].to_ascii_uppercase()).unwrap();
+    }
+}
+
+/// Sends a message through `addr` and checks that it comes back uppercased.
+async fn roundtrip(addr: String, message: &'static str) {
+    tokio::task::spawn_blocking(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(message.as_bytes()).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut echoed = String::new();
+        stream.read_to_string(&mut echoed).unwrap();
+        assert_eq!(echoed, message.to_uppercase());
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn local() {
+    let port = echo_server();
+    let sess = crate::authed_session().await;
+    let mut forward =
+        LocalForward::bind(&sess, "127.0.0.1:0".parse().unwrap(), "127.0.0.1", port).unwrap();
+    let addr = forward.local_addr().unwrap();
+    let mut reports = forward.reports();
+    let handle = forward.handle();
+    let task = tokio::spawn(forward.run());
+
+    let clients = (0..4)
+        .map(|i| {
+            tokio::task::spawn_blocking(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                let message = format!("hello {}", i).repeat(1000);
+                stream.write_all(message.as_bytes()).unwrap();
+                stream.shutdown(std::net::Shutdown::Write).unwrap();
+                let mut echoed = String::new();
+                stream.read_to_string(&mut echoed).unwrap();
+                assert_eq!(echoed, message.to_uppercase());
+            })
+        })
+        .collect::<Vec<_>>();
+    for client in clients {
+        client.await.unwrap();
+    }
+
+    for _ in 0..4 {
+        let report = reports.next().await.unwrap();
+        assert!(report.error.is_none(), "{:?}", report.error);
+        assert_eq!(report.stats.target, format!("127.0.0.1:{}", port));
+        assert_eq!(report.stats.sent, 7000);
+        assert_eq!(report.stats.received, 7000);
+    }
+    assert!(handle.tunnels().is_empty());
+
+    handle.shutdown();
+    task.await.unwrap().unwrap();
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[tokio::test]
+async fn local_refused() {
+    let unused = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = unused.local_addr().unwrap().port();
+    drop(unused);
+
+    let sess = crate::authed_session().await;
+    let mut forward =
+        LocalForward::bind(&sess, "127.0.0.1:0".parse().unwrap(), "127.0.0.1", port).unwrap();
+    let addr = forward.local_addr().unwrap();
+    let mut reports = forward.reports();
+    let handle = forward.handle();
+    let task = tokio::spawn(forward.run());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let report = reports.next().await.unwrap();
+    assert_eq!(report.error.unwrap().operation(), Some("channel_direct_tcpip"));
+    assert_eq!(stream.read(&mut [0]).unwrap(), 0);
+
+    handle.shutdown();
+    task.await.unwrap().unwrap();
+}
+
+/// Connects to the SOCKS proxy at `addr`, sends `request` and returns the
+/// stream along with the reply of `reply_len` bytes.
+fn socks_connect(addr: SocketAddr, request: &[u8], reply_len: usize) -> (TcpStream, Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request).unwrap();
+    let mut reply = vec![0; reply_len];
+    stream.read_exact(&mut reply).unwrap();
+    (stream, reply)
+}
+
+fn socks5_request(host: &[u8], port: u16) -> Vec<u8> {
+    let mut request = vec![5, 1, 0, 5, 1, 0, 3, host.len() as u8];
+    request.extend_from_slice(host);
+    request.extend_from_slice(&port.to_be_bytes());
+    request
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dynamic() {
+    let port = echo_server();
+    let sess = crate::authed_session().await;
+    let mut forward = DynamicForward::bind(&sess, "127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = forward.local_addr().unwrap();
+    let mut reports = forward.reports();
+    let handle = forward.handle();
+    let task = tokio::spawn(forward.run());
+
+    let mut socks5_ip = vec![5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1];
+    socks5_ip.extend_from_slice(&port.to_be_bytes());
+    let mut socks4a = vec![4, 1];
+    socks4a.extend_from_slice(&port.to_be_bytes());
+    socks4a.extend_from_slice(b"\0\0\0\x01user\x00127.0.0.1\0");
+    let requests = vec![
+        (socks5_ip, vec![5, 0, 5, 0], "127.0.0.1"),
+        (socks5_request(b"localhost", port), vec![5, 0, 5, 0], "localhost"),
+        (socks4a, vec![0, 0x5a], "127.0.0.1"),
+    ];
+    for (request, ok, host) in requests {
+        tokio::task::spawn_blocking(move || {
+            let reply_len = if ok[0] == 5 { 12 } else { 8 };
+            let (mut stream, reply) = socks_connect(addr, &request, reply_len);
+            assert_eq!(reply[..ok.len()], ok[..]);
+            stream.write_all(b"hello").unwrap();
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut echoed = String::new();
+            stream.read_to_string(&mut echoed).unwrap();
+            assert_eq!(echoed, "HELLO");
+        })
+        .await
+        .unwrap();
+        let report = reports.next().await.unwrap();
+        assert!(report.error.is_none(), "{:?}", report.error);
+        assert_eq!(report.stats.target, format!("{}:{}", host, port));
+        assert_eq!(report.stats.sent, 5);
+    }
+
+    handle.shutdown();
+    task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn dynamic_refused() {
+    let unused = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = unused.local_addr().unwrap().port();
+    drop(unused);
+
+    let sess = crate::authed_session().await;
+    let mut forward = DynamicForward::bind(&sess, "127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = forward.local_addr().unwrap();
+    let mut reports = forward.reports();
+    let handle = forward.handle();
+    let task = tokio::spawn(forward.run());
+
+    let client = tokio::task::spawn_blocking(move || {
+        let request = socks5_request(b"127.0.0.1", port);
+        let (_, reply) = socks_connect(addr, &request, 12);
+        assert_eq!(reply[..3], [5, 0, 5]);
+        assert_ne!(reply[3], 0);
+    });
+    let report = reports.next().await.unwrap();
+    assert_eq!(report.error.unwrap().operation(), Some("channel_direct_tcpip"));
+    client.await.unwrap();
+
+    // Only CONNECT is supported.
+    let mut bind = vec![5, 1, 0, 5, 2, 0, 1, 127, 0, 0, 1];
+    bind.extend_from_slice(&port.to_be_bytes());
+    tokio::task::spawn_blocking(move || {
+        let (_, reply) = socks_connect(addr, &bind, 12);
+        assert_eq!(reply[..4], [5, 0, 5, 7]);
+    })
+    .await
+    .unwrap();
+    assert!(reports.next().await.unwrap().error.is_some());
+
+    handle.shutdown();
+    task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn dynamic_handshake_timeout() {
+    // The session is only used once the client has sent its request.
+    let sess = Session::new().unwrap();
+    let mut forward = DynamicForward::bind(&sess, "127.0.0.1:0".parse().unwrap()).unwrap();
+    forward.set_handshake_timeout(Duration::from_millis(100));
+    let addr = forward.local_addr().unwrap();
+    let mut reports = forward.reports();
+    let handle = forward.handle();
+    let task = tokio::spawn(forward.run());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let report = reports.next().await.unwrap();
+    assert!(report.error.unwrap().is_timeout());
+    assert_eq!(report.stats.target, "");
+    assert!(handle.tunnels().is_empty());
+    assert_eq!(stream.read(&mut [0]).unwrap(), 0);
+
+    handle.shutdown();
+    task.await.unwrap().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn remote() {
+    let port = echo_server();
+    let sess = crate::authed_session().await;
+    let target = ForwardTarget::Tcp("127.0.0.1".into(), port);
+    let mut forward = RemoteForward::bind(&sess, Some("127.0.0.1"), 0, target)
+        .await
+        .unwrap();
+    let addr = forward.remote_addr().to_owned();
+    let mut reports = forward.reports();
+    let handle = forward.handle();
+    let task = tokio::spawn(forward.run());
+
+    roundtrip(addr.clone(), "hello").await;
+    roundtrip(addr.clone(), "world").await;
+    for _ in 0..2 {
+        let report = reports.next().await.unwrap();
+        assert!(report.error.is_none(), "{:?}", report.error);
+        assert_eq!(report.stats.peer, addr);
+        assert_eq!(report.stats.target, format!("127.0.0.1:{}", port));
+        assert_eq!(report.stats.sent, 5);
+    }
+
+    handle.shutdown();
+    task.await.unwrap().unwrap();
+    assert!(TcpStream::connect(&addr).is_err());
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn remote_unix() {
+    use std::os::unix::net::UnixListener;
+
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("echo.sock");
+    let listener = UnixListener::bind(&path).unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            thread::spawn(move || echo(stream.unwrap()));
+        }
+    });
+
+    let sess = crate::authed_session().await;
+    let target = ForwardTarget::Unix(path.clone());
+    let forward = RemoteForward::bind(&sess, Some("127.0.0.1"), 0, target)
+        .await
+        .unwrap();
+    let addr = forward.remote_addr().to_owned();
+    let handle = forward.handle();
+    let task = tokio::spawn(forward.run());
+
+    roundtrip(addr, "over a unix socket").await;
+
+    handle.shutdown();
+    task.await.unwrap().unwrap();
+}
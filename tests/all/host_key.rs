@@ -0,0 +1,117 @@
+use async_ssh2::{
+    ConnectOptions, Error, FingerprintVerifier, HashType, HostKeyErrorKind, InsecureAcceptAll,
+    KnownHostsFile, KnownHostsVerifier, Session,
+};
+use std::fs;
+use tempfile::tempdir;
+
+async fn connect(options: &ConnectOptions) -> Result<Session, Error> {
+    Session::connect("127.0.0.1", crate::test_port(), options).await
+}
+
+fn expect_rejected(res: Result<Session, Error>, kind: HostKeyErrorKind) {
+    match res {
+        Err(Error::HostKey(e)) => {
+            assert_eq!(e.kind(), kind);
+            assert_eq!(e.host(), "127.0.0.1");
+            assert_eq!(e.port(), crate::test_port());
+            assert!(e.fingerprint().starts_with("SHA256:"), "{}", e.fingerprint());
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("host key was accepted"),
+    }
+}
+
+#[tokio::test]
+async fn strict_unknown() {
+    let td = tempdir().unwrap();
+    let options = ConnectOptions::new()
+        .host_key_verifier(KnownHostsVerifier::strict(td.path().join("known_hosts")));
+    expect_rejected(connect(&options).await, HostKeyErrorKind::Unknown);
+}
+
+#[tokio::test]
+async fn accept_new() {
+    let td = tempdir().unwrap();
+    let path = td.path().join("ssh").join("known_hosts");
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, "# no trailing newline").unwrap();
+
+    let options = ConnectOptions::new().host_key_verifier(KnownHostsVerifier::accept_new(&path));
+    connect(&options).await.unwrap();
+    let contents = fs::read_to_string(&path).unwrap();
+    let lines = contents.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    let prefix = format!("[127.0.0.1]:{} ssh-rsa AAAA", crate::test_port());
+    assert!(lines[1].starts_with(&prefix), "{}", lines[1]);
+
+    // Known now, so strict checking passes and nothing is appended again.
+    let options = ConnectOptions::new().host_key_verifier(KnownHostsVerifier::strict(&path));
+    connect(&options).await.unwrap();
+    let options = ConnectOptions::new().host_key_verifier(KnownHostsVerifier::accept_new(&path));
+    connect(&options).await.unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+}
+
+#[tokio::test]
+async fn changed() {
+    let td = tempdir().unwrap();
+    let path = td.path().join("known_hosts");
+    fs::write(
+        &path,
+        format!(
+            "[127.0.0.1]:{} ssh-rsa AAAAB3NzaC1yc2EAAAABIwAAAQEAq2A7hRGmdnm9tUDbO9I\
+             DSwBK6TbQa+PXYPCPy6rbTrTtw7PHkccKrpp0yVhp5HdEIcKr6pLlVD\
+             BfOLX9QUsyCOV0wzfjIJNlGEYsdlLJizHhbn2mUjvSAHQqZETYP81eF\
+             zLQNnPHt4EVVUh7VfDESU84KezmD5QlWpXLmvU31/yMf+Se8xhHTvKS\
+             CZIFImWwoG6mbUoWf9nzpIoaSjB+weqqUUmpaaasXVal72J+UX2B+2R\
+             PW3RcT0eOzQgqlJL3RKrTJvdsjE3JEAvGq3lGHSZXy28G3skua2SmVi\
+             /w4yCE6gbODqnTWlg7+wC604ydGXA8VJiS5ap43JXiUFFAaQ==\n",
+            crate::test_port()
+        ),
+    )
+    .unwrap();
+    let before = fs::read_to_string(&path).unwrap();
+
+    let options = ConnectOptions::new().host_key_verifier(KnownHostsVerifier::accept_new(&path));
+    expect_rejected(connect(&options).await, HostKeyErrorKind::Changed);
+    assert_eq!(fs::read_to_string(&path).unwrap(), before);
+}
+
+#[tokio::test]
+async fn fingerprint() {
+    let options = ConnectOptions::new().host_key_verifier(InsecureAcceptAll);
+    let sess = connect(&options).await.unwrap();
+    let fingerprint = sess.host_key_hash(HashType::Sha256).unwrap().to_vec();
+
+    let pinned = FingerprintVerifier::new(HashType::Sha256)
+        .pin(&[0; 32])
+        .pin(&fingerprint);
+    connect(&ConnectOptions::new().host_key_verifier(pinned))
+        .await
+        .unwrap();
+
+    let other = FingerprintVerifier::new(HashType::Sha256).pin(&[0; 32]);
+    let res = connect(&ConnectOptions::new().host_key_verifier(other)).await;
+    expect_rejected(res, HostKeyErrorKind::NotPinned);
+}
+
+#[tokio::test]
+async fn revoked() {
+    let td = tempdir().unwrap();
+    let path = td.path().join("known_hosts");
+    let sess = connect(&ConnectOptions::new().host_key_verifier(InsecureAcceptAll))
+        .await
+        .unwrap();
+    let mut known_hosts = KnownHostsFile::open(&path).await.unwrap();
+    known_hosts
+        .add("127.0.0.1", crate::test_port(), sess.host_key().unwrap().0, true)
+        .unwrap();
+    known_hosts.save().await.unwrap();
+    let line = fs::read_to_string(&path).unwrap();
+    let key = line.split(' ').skip(1).collect::<Vec<_>>().join(" ");
+    fs::write(&path, format!("{}@revoked * {}", line, key)).unwrap();
+
+    let options = ConnectOptions::new().host_key_verifier(KnownHostsVerifier::strict(&path));
+    expect_rejected(connect(&options).await, HostKeyErrorKind::Revoked);
+}
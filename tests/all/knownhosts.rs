@@ -1,4 +1,16 @@
-use async_ssh2::{KnownHostFileKind, Session};
+use async_ssh2::{KnownHostFileKind, KnownHostMarker, KnownHostStatus, KnownHostsFile, Session};
+use std::fs;
+use tempfile::tempdir;
+
+const KEY1: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIAEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEB";
+const KEY2: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIAICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgIC";
+
+/// An ssh-ed25519 key blob filled with `byte`, matching `KEY1`/`KEY2`.
+fn key(byte: u8) -> Vec<u8> {
+    let mut blob = b"\0\0\0\x0bssh-ed25519\0\0\0\x20".to_vec();
+    blob.extend_from_slice(&[byte; 32]);
+    blob
+}
 
 #[test]
 fn smoke() {
@@ -45,9 +57,158 @@ PW3RcT0eOzQgqlJL3RKrTJvdsjE3JEAvGq3lGHSZXy28G3skua2SmVi\
 
     assert_eq!(
         known_hosts
-            .write_string(&host, KnownHostFileKind::OpenSSH)
+            .write_string(host, KnownHostFileKind::OpenSSH)
             .unwrap(),
         encoded
     );
     known_hosts.remove(host).unwrap();
 }
+
+#[tokio::test]
+async fn file_check() {
+    let td = tempdir().unwrap();
+    let path = td.path().join("known_hosts");
+    fs::write(
+        &path,
+        format!(
+            "# comment\n\
+             |1|AAECAwQFBgcICQoLDA0ODxAREhM=|nnUK16ANsXd3hL31YfAkGOluSjU= ssh-ed25519 {k1}\n\
+             [port.example.com]:2222 ssh-ed25519 {k1} some comment\n\
+             *.example.org,!bad.example.org ssh-ed25519 {k1}\n\
+             @cert-authority *.example.net ssh-ed25519 {k1}\n\
+             @revoked * ssh-ed25519 {k2}\n\
+             garbage\n",
+            k1 = KEY1,
+            k2 = KEY2
+        ),
+    )
+    .unwrap();
+
+    let known_hosts = KnownHostsFile::open(&path).await.unwrap();
+    let entries = known_hosts.entries().collect::<Vec<_>>();
+    assert_eq!(entries.len(), 5);
+    assert!(entries[0].is_hashed());
+    assert_eq!(entries[1].comment(), Some("some comment"));
+    assert_eq!(entries[3].marker(), Some(KnownHostMarker::CertAuthority));
+    assert_eq!(entries[4].marker(), Some(KnownHostMarker::Revoked));
+    assert_eq!(entries[0].key(), &key(1)[..]);
+    assert_eq!(entries[0].key_type(), "ssh-ed25519");
+
+    let (k1, k2, k3) = (key(1), key(2), key(3));
+    assert_eq!(known_hosts.check("example.com", 22, &k1), KnownHostStatus::Match);
+    assert_eq!(known_hosts.check("EXAMPLE.com", 22, &k3), KnownHostStatus::Mismatch);
+    assert_eq!(known_hosts.check("example.com", 2222, &k1), KnownHostStatus::NotFound);
+    assert_eq!(known_hosts.check("port.example.com", 2222, &k1), KnownHostStatus::Match);
+    assert_eq!(known_hosts.check("port.example.com", 22, &k1), KnownHostStatus::NotFound);
+    assert_eq!(known_hosts.check("a.example.org", 22, &k1), KnownHostStatus::Match);
+    assert_eq!(known_hosts.check("bad.example.org", 22, &k1), KnownHostStatus::NotFound);
+    assert_eq!(known_hosts.check("a.example.net", 22, &k1), KnownHostStatus::NotFound);
+    assert_eq!(known_hosts.check("example.com", 22, &k2), KnownHostStatus::Revoked);
+}
+
+#[tokio::test]
+async fn file_add_replace() {
+    let td = tempdir().unwrap();
+    let path = td.path().join("ssh").join("known_hosts");
+    let (k1, k2) = (key(1), key(2));
+
+    let mut known_hosts = KnownHostsFile::open(&path).await.unwrap();
+    assert_eq!(known_hosts.entries().count(), 0);
+    known_hosts.add("plain.example.com", 22, &k1, false).unwrap();
+    known_hosts.add("hashed.example.com", 2222, &k1, true).unwrap();
+    known_hosts.save().await.unwrap();
+
+    let contents = fs::read_to_string(&path).unwrap();
+    let lines = contents.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], format!("plain.example.com ssh-ed25519 {}", KEY1));
+    assert!(lines[1].starts_with("|1|"), "{}", lines[1]);
+    assert!(!lines[1].contains("hashed.example.com"));
+
+    let mut known_hosts = KnownHostsFile::open(&path).await.unwrap();
+    assert_eq!(known_hosts.check("hashed.example.com", 2222, &k1), KnownHostStatus::Match);
+    assert_eq!(known_hosts.check("hashed.example.com", 22, &k1), KnownHostStatus::NotFound);
+
+    known_hosts.replace("plain.example.com", 22, &k2, false).unwrap();
+    known_hosts.save().await.unwrap();
+    let known_hosts = KnownHostsFile::open(&path).await.unwrap();
+    assert_eq!(known_hosts.check("plain.example.com", 22, &k2), KnownHostStatus::Match);
+    assert_eq!(known_hosts.check("plain.example.com", 22, &k1), KnownHostStatus::Mismatch);
+    assert_eq!(known_hosts.entries().count(), 2);
+    // Untouched lines are written back verbatim.
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().next(), Some(lines[1]));
+
+    let mut known_hosts = known_hosts;
+    assert_eq!(known_hosts.remove("hashed.example.com", 2222), 1);
+    known_hosts.save().await.unwrap();
+    assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_saves() {
+    let td = tempdir().unwrap();
+    let path = td.path().join("known_hosts");
+
+    let saves = (0..8u8)
+        .map(|i| {
+            let path = path.clone();
+            tokio::spawn(async move {
+                let mut known_hosts = KnownHostsFile::open(&path).await.unwrap();
+                for port in 0..100 {
+                    let host = format!("host{}.example.com", i);
+                    known_hosts.add(&host, port, &key(1), false).unwrap();
+                }
+                known_hosts.save().await.unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    for save in saves {
+        save.await.unwrap();
+    }
+
+    // The file is one of the saves as a whole, not a mix of several.
+    let known_hosts = KnownHostsFile::open(&path).await.unwrap();
+    assert_eq!(known_hosts.entries().count(), 100);
+    let first = known_hosts.entries().next().unwrap().hosts();
+    let host = first.trim_start_matches('[').split('.').next().unwrap();
+    let prefix = format!("[{}.", host);
+    assert!(known_hosts
+        .entries()
+        .all(|entry| entry.hosts().starts_with(&prefix) || entry.hosts().starts_with(&prefix[1..])));
+    assert_eq!(fs::read_dir(td.path()).unwrap().count(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_appends() {
+    let td = tempdir().unwrap();
+    let path = td.path().join("known_hosts");
+    // A last line without its newline must not swallow the first append.
+    fs::write(&path, format!("old.example.com ssh-ed25519 {}", KEY1)).unwrap();
+
+    // Like concurrent connections accepting new hosts: each opened the file
+    // before the others wrote to it.
+    let mut files = Vec::new();
+    for _ in 0..8 {
+        files.push(KnownHostsFile::open(&path).await.unwrap());
+    }
+    let appends = files
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut known_hosts)| {
+            tokio::spawn(async move {
+                let host = format!("host{}.example.com", i);
+                known_hosts.append(&host, 22, &key(2), false).await.unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    for append in appends {
+        append.await.unwrap();
+    }
+
+    let known_hosts = KnownHostsFile::open(&path).await.unwrap();
+    assert_eq!(known_hosts.entries().count(), 9);
+    assert_eq!(known_hosts.check("old.example.com", 22, &key(1)), KnownHostStatus::Match);
+    for i in 0..8 {
+        let host = format!("host{}.example.com", i);
+        assert_eq!(known_hosts.check(&host, 22, &key(2)), KnownHostStatus::Match);
+    }
+}
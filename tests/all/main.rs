@@ -8,15 +8,22 @@ use std::{env, net::{TcpStream, ToSocketAddrs}};
 
 mod agent;
 mod channel;
+mod forward;
+mod host_key;
 mod knownhosts;
+mod process;
 mod session;
 mod sftp;
+mod ssh_config;
 
-pub fn test_addr() -> String {
-    let port = env::var("RUST_SSH2_FIXTURE_PORT")
+pub fn test_port() -> u16 {
+    env::var("RUST_SSH2_FIXTURE_PORT")
         .map(|s| s.parse().unwrap())
-        .unwrap_or(22);
-    let addr = format!("127.0.0.1:{}", port);
+        .unwrap_or(22)
+}
+
+pub fn test_addr() -> String {
+    let addr = format!("127.0.0.1:{}", test_port());
     addr
 }
 
@@ -37,7 +44,7 @@ pub async fn authed_session() -> async_ssh2::Session {
         agent.connect().await.unwrap();
         agent.list_identities().unwrap();
         let identity = &agent.identities().unwrap()[0];
-        agent.userauth(&user, &identity).await.unwrap();
+        agent.userauth(&user, identity).await.unwrap();
     }
     assert!(sess.authenticated());
     sess
@@ -0,0 +1,83 @@
+use async_ssh2::Session;
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+#[tokio::test]
+async fn output() {
+    let sess = crate::authed_session().await;
+    let output = sess
+        .command("printf")
+        .args(["%s|", "it's", "a b", "$HOME", ""])
+        .env("ASYNC_SSH2_TEST", "x y")
+        .current_dir("/")
+        .output()
+        .await
+        .unwrap();
+    assert!(output.success());
+    assert_eq!(output.stdout, b"it's|a b|$HOME||");
+
+    let output = sess
+        .command("sh")
+        .args(["-c", "echo \"$ASYNC_SSH2_TEST\"; pwd"])
+        .env("ASYNC_SSH2_TEST", "x 'y'")
+        .current_dir("/tmp")
+        .output()
+        .await
+        .unwrap();
+    assert_eq!(output.stdout, b"x 'y'\n/tmp\n");
+
+    // Run as a program, not taken for an assignment.
+    let output = sess.command("ASYNC_SSH2_TEST=x").output().await.unwrap();
+    assert_eq!(output.status, 127);
+}
+
+#[tokio::test]
+async fn invalid_env_name() {
+    // Rejected before anything is sent, so no server is needed.
+    let sess = Session::new().unwrap();
+    for key in ["", "1A", "A B", "A;B", "A=B"] {
+        let err = sess.command("true").env(key, "x").output().await.unwrap_err();
+        assert!(err.to_string().contains("invalid environment variable name"), "{}", err);
+    }
+}
+
+#[tokio::test]
+async fn spawn() {
+    let sess = crate::authed_session().await;
+    let mut child = sess
+        .command("sh")
+        .args(["-c", "cat; echo done >&2; exit 7"])
+        .spawn()
+        .await
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+
+    let writer = tokio::spawn(async move {
+        stdin.write_all(&[b'x'; 100_000]).await.unwrap();
+        stdin.close().await.unwrap();
+    });
+    let reader = tokio::spawn(async move {
+        let mut out = Vec::new();
+        stdout.read_to_end(&mut out).await.unwrap();
+        out
+    });
+    let mut err = String::new();
+    stderr.read_to_string(&mut err).await.unwrap();
+    writer.await.unwrap();
+    assert_eq!(reader.await.unwrap().len(), 100_000);
+    assert_eq!(err, "done\n");
+
+    let status = child.wait().await.unwrap();
+    assert_eq!(status.code(), 7);
+    assert!(!status.success());
+}
+
+#[tokio::test]
+async fn kill() {
+    let sess = crate::authed_session().await;
+    let mut child = sess.command("sleep").arg("600").spawn().await.unwrap();
+    child.kill().await.unwrap();
+    let status = child.wait().await.unwrap();
+    assert!(!status.success());
+}
@@ -1,7 +1,27 @@
-use async_ssh2::Session;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
-use ssh2::{HashType, MethodType};
-use std::{env, fs::File, io::prelude::*, path::Path};
+use async_io::Async;
+use async_ssh2::{
+    with_timeout, AsyncKeyboardInteractivePrompt, ConnectError, ConnectOptions, Error, ErrorKind,
+    InsecureAcceptAll, OperationError, Session,
+};
+use futures::{
+    future::{BoxFuture, FutureExt},
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+use ssh2::{HashType, KeyboardInteractivePrompt, MethodType, Prompt};
+use std::{
+    env,
+    error::Error as _,
+    fs::File,
+    io::prelude::*,
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use tempfile::tempdir;
 
 #[test]
@@ -14,6 +34,16 @@ fn session_is_send() {
     assert!(must_be_send(&sess));
 }
 
+#[test]
+fn session_is_sync() {
+    fn must_be_sync<T: Sync>(_: &T) -> bool {
+        true
+    }
+
+    let sess = Session::new().unwrap();
+    assert!(must_be_sync(&sess));
+}
+
 #[tokio::test]
 async fn smoke() {
     let socket = crate::socket().await;
@@ -34,6 +64,148 @@ async fn smoke() {
     sess.channel_session().await.err().unwrap();
 }
 
+#[tokio::test]
+async fn connect() {
+    let options = ConnectOptions::new()
+        .connect_timeout(Duration::from_secs(5))
+        .host_key_verifier(InsecureAcceptAll);
+    let sess = Session::connect("localhost", crate::test_port(), &options)
+        .await
+        .unwrap();
+    sess.host_key().unwrap();
+    assert!(!sess.authenticated());
+}
+
+#[tokio::test]
+async fn connect_refused() {
+    let port = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    };
+    let options = ConnectOptions::new().happy_eyeballs(false);
+    match Session::connect("127.0.0.1", port, &options).await {
+        Err(ref err @ Error::Connect(ref e)) => {
+            assert_eq!(err.kind(), ErrorKind::ConnectFailed);
+            assert!(err.is_retryable());
+            assert_eq!(e.port(), port);
+            assert_eq!(e.attempts().len(), 1);
+            assert_eq!(e.attempts()[0].0, SocketAddr::from(([127, 0, 0, 1], port)));
+            assert!(e.to_string().contains(&format!("127.0.0.1:{}", port)));
+            let source = err.source().unwrap().downcast_ref::<ConnectError>().unwrap();
+            assert_eq!(source.port(), port);
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("connected to a closed port"),
+    }
+}
+
+#[tokio::test]
+async fn connect_via() {
+    let user = env::var("USER").unwrap();
+    let jump = crate::authed_session().await;
+    let options = ConnectOptions::new().host_key_verifier(InsecureAcceptAll);
+    let hop = Session::connect_via(&jump, "127.0.0.1", crate::test_port(), &options)
+        .await
+        .unwrap();
+    hop.userauth_agent(&user).await.unwrap();
+    let target = Session::connect_via(&hop, "127.0.0.1", crate::test_port(), &options)
+        .await
+        .unwrap();
+    target.userauth_agent(&user).await.unwrap();
+    assert!(target.authenticated());
+
+    let mut channel = target.channel_session().await.unwrap();
+    channel.exec("echo foo").await.unwrap();
+    let mut output = String::new();
+    channel.read_to_string(&mut output).await.unwrap();
+    assert_eq!(output, "foo\n");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn connect_command() {
+    let user = env::var("USER").unwrap();
+    let mut relay = Command::new(env!("CARGO_BIN_EXE_proxy-relay"));
+    relay.arg(crate::test_port().to_string());
+    let options = ConnectOptions::new().host_key_verifier(InsecureAcceptAll);
+    let sess = Session::connect_command("127.0.0.1", crate::test_port(), relay, &options)
+        .await
+        .unwrap();
+    sess.userauth_agent(&user).await.unwrap();
+    assert!(sess.authenticated());
+
+    let mut channel = sess.channel_session().await.unwrap();
+    channel.exec("echo foo").await.unwrap();
+    let mut output = String::new();
+    channel.read_to_string(&mut output).await.unwrap();
+    assert_eq!(output, "foo\n");
+    assert_eq!(sess.proxy_stderr().unwrap(), "relaying\n");
+}
+
+#[tokio::test]
+async fn operation_timeout() {
+    // Accepts connections but never sends a banner.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let options = ConnectOptions::new()
+        .host_key_verifier(InsecureAcceptAll)
+        .operation_timeout(Duration::from_millis(200));
+    let err = Session::connect("127.0.0.1", port, &options).await.err().unwrap();
+    assert!(err.is_timeout(), "{}", err);
+    assert_eq!(err.operation(), Some("handshake"));
+
+    let stream = Async::<TcpStream>::connect(listener.local_addr().unwrap()).await.unwrap();
+    let mut sess = Session::new().unwrap();
+    sess.set_tcp_stream(stream).unwrap();
+    assert_eq!(sess.operation_timeout(), None);
+    let handshake = with_timeout(Some(Duration::from_millis(100)), sess.handshake());
+    assert_eq!(handshake.await.unwrap_err().kind(), ErrorKind::Timeout);
+    assert!(sess.is_dead());
+}
+
+#[tokio::test]
+async fn canceled() {
+    // Accepts connections but never sends a banner.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = Async::<TcpStream>::connect(listener.local_addr().unwrap()).await.unwrap();
+    let mut sess = Session::new().unwrap();
+    sess.set_tcp_stream(stream).unwrap();
+
+    assert!(sess.handshake().now_or_never().is_none());
+    let err = sess.channel_session().await.err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::Interrupted);
+    assert!(err.to_string().contains("handshake"), "{}", err);
+    // Retrying the interrupted operation resumes it.
+    let handshake = with_timeout(Some(Duration::from_millis(100)), sess.handshake());
+    assert_eq!(handshake.await.unwrap_err().kind(), ErrorKind::Timeout);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn connect_command_failure() {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg("echo 'no route to host' >&2; exit 1");
+    let options = ConnectOptions::new();
+    match Session::connect_command("127.0.0.1", 22, command, &options).await {
+        Err(ref err @ Error::ProxyCommand(ref e)) => {
+            assert_eq!(err.operation(), Some("handshake"));
+            assert_eq!(err.peer(), Some("127.0.0.1:22"));
+            assert_eq!(e.program(), "sh");
+            assert_eq!(e.stderr(), "no route to host\n");
+            assert!(e.to_string().contains("no route to host"));
+            // The messages name the cause, which the chain leads to as well.
+            let op = err.source().unwrap().downcast_ref::<OperationError>().unwrap();
+            assert_eq!(op.operation(), "handshake");
+            let cause = op.source().unwrap();
+            assert!(e.to_string().contains(&cause.to_string()));
+            assert!(op.to_string().contains(&cause.to_string()));
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("handshake succeeded without a server"),
+    }
+}
+
 #[tokio::test]
 async fn smoke_handshake() {
     let user = env::var("USER").unwrap();
@@ -51,26 +223,45 @@ async fn smoke_handshake() {
     agent.list_identities().unwrap();
     {
         let identity = &agent.identities().unwrap()[0];
-        agent.userauth(&user, &identity).await.unwrap();
+        agent.userauth(&user, identity).await.unwrap();
     }
     assert!(sess.authenticated());
     sess.host_key_hash(HashType::Md5).unwrap();
 }
 
-/*
-#[test]
-fn keyboard_interactive() {
+#[tokio::test]
+async fn set_stream() {
     let user = env::var("USER").unwrap();
-    let socket = ::socket();
+    let socket = crate::socket().await;
     let mut sess = Session::new().unwrap();
-    sess.set_tcp_stream(socket);
-    sess.handshake().unwrap();
+    sess.set_stream(socket).unwrap();
+    sess.handshake().await.unwrap();
     sess.host_key().unwrap();
-    let methods = sess.auth_methods(&user).unwrap();
+    sess.userauth_agent(&user).await.unwrap();
+    assert!(sess.authenticated());
+
+    let mut channel = sess.channel_session().await.unwrap();
+    channel.exec("head -c 300000 /dev/zero").await.unwrap();
+    let mut output = Vec::new();
+    channel.read_to_end(&mut output).await.unwrap();
+    assert_eq!(output.len(), 300000);
+    channel.wait_close().await.unwrap();
+    assert_eq!(channel.exit_status().unwrap(), 0);
+}
+
+#[tokio::test]
+async fn keyboard_interactive() {
+    let user = env::var("USER").unwrap();
+    let socket = crate::socket().await;
+    let mut sess = Session::new().unwrap();
+    sess.set_tcp_stream(socket).unwrap();
+    sess.handshake().await.unwrap();
+    sess.host_key().unwrap();
+    let methods = sess.auth_methods(&user).await.unwrap();
     assert!(
         methods.contains("keyboard-interactive"),
         "test server ({}) must support `ChallengeResponseAuthentication yes`, not just {}",
-        ::test_addr(),
+        crate::test_addr(),
         methods
     );
     assert!(!sess.authenticated());
@@ -110,7 +301,7 @@ fn keyboard_interactive() {
                 assert_eq!(prompts.len(), 1);
                 // Might be "Password: " or "Password:" or other variations
                 assert!(prompts[0].text.contains("sword"));
-                assert_eq!(prompts[0].echo, false);
+                assert!(!prompts[0].echo);
             } else {
                 // maybe there's some PAM configuration that results
                 // in multiple prompts. We can't make any real assertions
@@ -125,7 +316,7 @@ fn keyboard_interactive() {
 
     let mut p = Prompter { some_data: 42 };
 
-    match sess.userauth_keyboard_interactive(&user, &mut p) {
+    match sess.userauth_keyboard_interactive(&user, &mut p).await {
         Ok(_) => eprintln!("auth succeeded somehow(!)"),
         Err(err) => eprintln!("auth failed as expected: {}", err),
     };
@@ -134,7 +325,43 @@ fn keyboard_interactive() {
     // running these tests has "bogus" as their password
     assert!(!sess.authenticated());
 }
-*/
+
+#[tokio::test]
+async fn keyboard_interactive_async() {
+    let user = env::var("USER").unwrap();
+    let socket = crate::socket().await;
+    let mut sess = Session::new().unwrap();
+    sess.set_tcp_stream(socket).unwrap();
+    sess.handshake().await.unwrap();
+
+    struct Prompter {
+        rounds: usize,
+    }
+
+    impl AsyncKeyboardInteractivePrompt for Prompter {
+        fn prompt<'a>(
+            &'a mut self,
+            _username: &'a str,
+            _instructions: &'a str,
+            prompts: &'a [Prompt<'a>],
+        ) -> BoxFuture<'a, Vec<String>> {
+            Box::pin(async move {
+                // Simulate waiting on an external source for the answers
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                self.rounds += 1;
+                prompts.iter().map(|_| "bogus".to_string()).collect()
+            })
+        }
+    }
+
+    let mut p = Prompter { rounds: 0 };
+    assert!(sess
+        .userauth_keyboard_interactive(&user, &mut p)
+        .await
+        .is_err());
+    assert!(p.rounds > 0);
+    assert!(!sess.authenticated());
+}
 
 #[tokio::test]
 async fn keepalive() {
@@ -143,6 +370,74 @@ async fn keepalive() {
     sess.keepalive_send().await.unwrap();
 }
 
+#[tokio::test]
+async fn keepalive_task() {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let user = env::var("USER").unwrap();
+    // Relays to the fixture until `frozen` is set, then swallows everything
+    // the server sends, like a NAT that dropped the connection.
+    let frozen = Arc::new(AtomicBool::new(false));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let relay_frozen = frozen.clone();
+    tokio::spawn(async move {
+        let (client, _) = listener.accept().await.unwrap();
+        let server = tokio::net::TcpStream::connect(crate::test_addr()).await.unwrap();
+        let (mut client_read, mut client_write) = client.into_split();
+        let (mut server_read, mut server_write) = server.into_split();
+        tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut client_read, &mut server_write).await;
+        });
+        let mut buf = [0; 4096];
+        loop {
+            let n = server_read.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            if !relay_frozen.load(Ordering::SeqCst) {
+                client_write.write_all(&buf[..n]).await.unwrap();
+            }
+        }
+    });
+
+    let options = ConnectOptions::new().host_key_verifier(InsecureAcceptAll);
+    let sess = Session::connect("127.0.0.1", port, &options).await.unwrap();
+    sess.userauth_agent(&user).await.unwrap();
+    let task = tokio::spawn(sess.keepalive_task(1, 2));
+    // Replies the application reads along with its own data count too,
+    // however many keepalives go out while it does.
+    let mut channel = sess.channel_session().await.unwrap();
+    channel.exec("cat").await.unwrap();
+    let start = Instant::now();
+    let mut line = [0; 5];
+    while start.elapsed() < Duration::from_secs(4) {
+        AsyncWriteExt::write_all(&mut channel, b"ping\n").await.unwrap();
+        AsyncReadExt::read_exact(&mut channel, &mut line).await.unwrap();
+        assert_eq!(&line, b"ping\n");
+    }
+    assert!(!sess.is_dead());
+
+    frozen.store(true, Ordering::SeqCst);
+    tokio::time::timeout(Duration::from_secs(10), sess.wait_dead())
+        .await
+        .unwrap();
+    assert!(sess.is_dead());
+    assert!(task.await.unwrap().is_err());
+    assert!(sess.channel_session().await.is_err());
+
+    // The task stops as soon as the session is gone, not at its next
+    // keepalive.
+    let sess = crate::authed_session().await;
+    let task = tokio::spawn(sess.keepalive_task(60, 2));
+    drop(sess);
+    tokio::time::timeout(Duration::from_secs(5), task)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
 #[tokio::test]
 async fn scp_recv() {
     let sess = crate::authed_session().await;
@@ -177,7 +472,7 @@ async fn scp_send() {
     std::thread::sleep(std::time::Duration::from_millis(100));
 
     let mut actual = Vec::new();
-    File::open(&td.path().join("foo"))
+    File::open(td.path().join("foo"))
         .unwrap()
         .read_to_end(&mut actual)
         .unwrap();
@@ -1,29 +1,67 @@
-use futures::io::{AsyncReadExt, AsyncWriteExt};
+use async_ssh2::ErrorKind;
+use futures::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    FutureExt,
+};
 use std::{
     fs::{self, File},
     io::prelude::*,
+    sync::Arc,
 };
 use tempfile::tempdir;
-use tokio;
 
 #[tokio::test]
 async fn smoke() {
     let sess = crate::authed_session().await;
-    sess.sftp().await.unwrap();
+    let sftp = sess.sftp().await.unwrap();
+
+    fn must_be_sync<T: Send + Sync>(_: &T) -> bool {
+        true
+    }
+    assert!(must_be_sync(&sftp));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shared() {
+    let td = tempdir().unwrap();
+    for i in 0..16 {
+        fs::write(td.path().join(i.to_string()), vec![0; i]).unwrap();
+    }
+
+    let sess = crate::authed_session().await;
+    let sftp = Arc::new(sess.sftp().await.unwrap());
+    let tasks = (0..16)
+        .map(|i| {
+            let sftp = sftp.clone();
+            let path = td.path().join(i.to_string());
+            tokio::spawn(async move {
+                for _ in 0..8 {
+                    assert_eq!(sftp.stat(&path).await.unwrap().size, Some(i as u64));
+                }
+                let mut file = sftp.open(&path).await.unwrap();
+                let mut contents = Vec::new();
+                file.read_to_end(&mut contents).await.unwrap();
+                assert_eq!(contents.len(), i);
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap();
+    }
 }
 
 #[tokio::test]
 async fn ops() {
     let td = tempdir().unwrap();
-    File::create(&td.path().join("foo")).unwrap();
-    fs::create_dir(&td.path().join("bar")).unwrap();
+    File::create(td.path().join("foo")).unwrap();
+    fs::create_dir(td.path().join("bar")).unwrap();
 
     let sess = crate::authed_session().await;
     let sftp = sess.sftp().await.unwrap();
     sftp.opendir(&td.path().join("bar")).await.unwrap();
     let mut foo = sftp.open(&td.path().join("foo")).await.unwrap();
     sftp.mkdir(&td.path().join("bar2"), 0o755).await.unwrap();
-    assert!(fs::metadata(&td.path().join("bar2"))
+    assert!(fs::metadata(td.path().join("bar2"))
         .map(|m| m.is_dir())
         .unwrap_or(false));
     sftp.rmdir(&td.path().join("bar2")).await.unwrap();
@@ -35,7 +73,7 @@ async fn ops() {
         .await
         .unwrap();
     let mut v = Vec::new();
-    File::open(&td.path().join("foo5"))
+    File::open(td.path().join("foo5"))
         .unwrap()
         .read_to_end(&mut v)
         .unwrap();
@@ -59,9 +97,62 @@ async fn ops() {
     let realpath = sftp.realpath(&td.path().join("foo2")).await.unwrap();
     assert_eq!(realpath, td.path().join("foo").canonicalize().unwrap());
 
-    let files = sftp.readdir(&td.path()).await.unwrap();
+    let files = sftp.readdir(td.path()).await.unwrap();
     assert_eq!(files.len(), 4);
 
+    let err = sftp.stat(&td.path().join("missing")).await.unwrap_err();
+    assert!(err.is_not_found(), "{}", err);
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert_eq!(err.operation(), Some("stat"));
+    assert_eq!(err.target(), Some(&*td.path().join("missing").to_string_lossy()));
+    assert!(err.peer().is_some());
+    assert!(err.to_string().contains("missing"), "{}", err);
+    let mut bar = sftp.opendir(&td.path().join("bar")).await.unwrap();
+    while bar.readdir().await.is_ok() {}
+    assert_eq!(bar.readdir().await.unwrap_err().kind(), ErrorKind::Eof);
+
     // This test fails, see FIXME in the implementation
     //sftp.shutdown().await.unwrap();
 }
+
+#[tokio::test]
+async fn canceled() {
+    let td = tempdir().unwrap();
+    let foo = td.path().join("foo");
+    File::create(&foo).unwrap();
+
+    let sess = crate::authed_session().await;
+    let sftp = sess.sftp().await.unwrap();
+    assert!(sftp.open(&foo).now_or_never().is_none());
+    let err = sftp.stat(&foo).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Interrupted);
+
+    sftp.open(&foo).await.unwrap();
+    sftp.stat(&foo).await.unwrap();
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn tokio_io() {
+    use tokio::io::{AsyncSeekExt, SeekFrom};
+
+    let td = tempdir().unwrap();
+    let path = td.path().join("foo");
+    let sess = crate::authed_session().await;
+    let sftp = sess.sftp().await.unwrap();
+
+    let mut file = sftp.create(&path).await.unwrap();
+    tokio::io::copy(&mut &b"0123456789"[..], &mut file).await.unwrap();
+    tokio::io::AsyncWriteExt::shutdown(&mut file).await.unwrap();
+
+    let mut file = sftp.open(&path).await.unwrap();
+    assert_eq!(AsyncSeekExt::seek(&mut file, SeekFrom::End(-3)).await.unwrap(), 7);
+    let mut end = String::new();
+    tokio::io::AsyncReadExt::read_to_string(&mut file, &mut end)
+        .await
+        .unwrap();
+    assert_eq!(end, "789");
+    assert_eq!(AsyncSeekExt::seek(&mut file, SeekFrom::Start(2)).await.unwrap(), 2);
+    assert_eq!(AsyncSeekExt::seek(&mut file, SeekFrom::Current(3)).await.unwrap(), 5);
+    assert!(AsyncSeekExt::seek(&mut file, SeekFrom::End(-11)).await.is_err());
+}
@@ -0,0 +1,100 @@
+use async_ssh2::{SshConfig, StrictHostKeyChecking};
+use std::{fs, path::Path, time::Duration};
+use tempfile::tempdir;
+
+#[tokio::test]
+async fn resolve() {
+    let td = tempdir().unwrap();
+    fs::create_dir(td.path().join("config.d")).unwrap();
+    fs::write(
+        td.path().join("config.d").join("10-db"),
+        "Host db\n\
+         \x20   HostName db.internal\n\
+         \x20   User dba\n",
+    )
+    .unwrap();
+    fs::write(
+        td.path().join("config"),
+        "# global defaults come last\n\
+         Include config.d/*\n\
+         \n\
+         Host web web-*  !web-legacy\n\
+         \x20   HostName %h.example.com\n\
+         \x20   Port=2222\n\
+         \x20   IdentityFile ~/.ssh/web\n\
+         \x20   ProxyJump bastion\n\
+         \x20   StrictHostKeyChecking accept-new\n\
+         \n\
+         Host legacy\n\
+         \x20   ProxyCommand nc %h %p\n\
+         \x20   ProxyJump bastion\n\
+         \n\
+         Match host \"*.example.com\" !user dba\n\
+         \x20   ServerAliveInterval 15\n\
+         \x20   Ciphers ^aes256-ctr\n\
+         \n\
+         Match originalhost db final\n\
+         \x20   ConnectTimeout 5\n\
+         \n\
+         Host *\n\
+         \x20   User deploy\n\
+         \x20   Port 22\n\
+         \x20   IdentityFile \"~/.ssh/id ed25519\"\n\
+         \x20   ForwardAgent yes # trailing comment\n",
+    )
+    .unwrap();
+    let config = SshConfig::read(td.path().join("config")).await.unwrap();
+
+    let web = config.resolve("web-1");
+    assert_eq!(web.host(), "web-1");
+    assert_eq!(web.host_name(), "web-1.example.com");
+    assert_eq!(web.port(), 2222);
+    assert_eq!(web.user(), Some("deploy"));
+    assert_eq!(web.proxy_jump(), Some("bastion"));
+    assert_eq!(web.proxy_command(), None);
+    assert_eq!(web.strict_host_key_checking(), StrictHostKeyChecking::AcceptNew);
+    assert_eq!(web.server_alive_interval(), Some(Duration::from_secs(15)));
+    assert_eq!(web.ciphers(), Some("^aes256-ctr"));
+    assert_eq!(web.get("forwardagent").unwrap(), ["yes"]);
+    let identities = web.identity_files();
+    assert_eq!(identities.len(), 2);
+    assert!(identities[0].ends_with(Path::new(".ssh").join("web")));
+    assert!(identities[1].ends_with(Path::new(".ssh").join("id ed25519")));
+
+    let legacy = config.resolve("web-legacy");
+    assert_eq!(legacy.host_name(), "web-legacy");
+    assert_eq!(legacy.port(), 22);
+    assert_eq!(legacy.server_alive_interval(), None);
+
+    let nc = config.resolve("legacy");
+    assert_eq!(nc.proxy_command().unwrap(), "nc legacy 22");
+    assert_eq!(nc.proxy_jump(), None);
+
+    let db = config.resolve("db");
+    assert_eq!(db.host_name(), "db.internal");
+    assert_eq!(db.user(), Some("dba"));
+    assert_eq!(db.connect_timeout(), Some(Duration::from_secs(5)));
+    assert_eq!(db.strict_host_key_checking(), StrictHostKeyChecking::Ask);
+    assert_eq!(db.identity_files().len(), 1);
+
+    let other = config.resolve("other");
+    assert_eq!(other.host_name(), "other");
+    assert_eq!(other.connect_timeout(), None);
+}
+
+#[tokio::test]
+async fn invalid() {
+    let td = tempdir().unwrap();
+    let path = td.path().join("config");
+    assert!(SshConfig::read(&path).await.unwrap().resolve("a").get("user").is_none());
+
+    for contents in &["Host a\n  Port ssh\n", "Match foo bar\n", "Host \"a\n", "Host\n"] {
+        fs::write(&path, contents).unwrap();
+        let err = SshConfig::read(&path).await.unwrap_err();
+        assert!(err.to_string().contains(":"), "{}", err);
+    }
+
+    fs::write(&path, "Include config\n").unwrap();
+    let err = SshConfig::read(&path).await.unwrap_err();
+    assert!(err.to_string().contains("Include"), "{}", err);
+}
@@ -0,0 +1,26 @@
+//! The proxy command of the `connect_command` test: relays between stdin and
+//! stdout and the fixture on the port given as its argument, in a single
+//! process so that killing it leaves nothing behind.
+
+use std::{
+    env,
+    io::{self, Write},
+    net::{Shutdown, TcpStream},
+    process, thread,
+};
+
+fn main() {
+    let port = env::args().nth(1).expect("usage: proxy-relay <port>");
+    eprintln!("relaying");
+    let server = TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
+    let mut to_server = server.try_clone().unwrap();
+    thread::spawn(move || {
+        let _ = io::copy(&mut io::stdin(), &mut to_server);
+        let _ = to_server.shutdown(Shutdown::Write);
+    });
+    let mut stdout = io::stdout();
+    let _ = io::copy(&mut &server, &mut stdout);
+    let _ = stdout.flush();
+    // Don't wait for stdin to close.
+    process::exit(0);
+}
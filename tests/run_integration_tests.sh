@@ -58,3 +58,4 @@ sleep 2
 # Run the tests against it
 cargo test --all -- --nocapture
 cargo test --features vendored-openssl -- --nocapture
+cargo test --features tokio -- --nocapture